@@ -5,8 +5,10 @@
 
 import { request, gql } from 'graphql-request';
 import { spawnSync } from 'child_process';
+import { createHash } from 'crypto';
 import { fileURLToPath } from 'url';
 import path from 'path';
+import { signSnapshot, snapshotMessage, IcpSnapshot } from './icp-signing.js';
 
 const REEF_EXPLORER_URL = 'https://squid.subsquid.io/reef-explorer/graphql';
 const REEF_DECIMALS = 18n;
@@ -147,16 +149,19 @@ function getDfxBin(): string {
   return process.env.DFX_BIN ?? 'dfx';
 }
 
-function ingestSnapshot(snapshot: {
-  ts: string;
-  active: number;
-  new_wallets: number;
-  extrinsics: number;
-}) {
+function candidBlob(bytes: Buffer): string {
+  return `blob "${Array.from(bytes, (b) => `\\${b.toString(16).padStart(2, '0')}`).join('')}"`;
+}
+
+function ingestSnapshot(snapshot: IcpSnapshot, idempotencyKey: string) {
   const network = process.env.DFX_NETWORK ?? 'ic';
   const identity = process.env.DFX_IDENTITY;
   const canister = process.env.ICP_CANISTER ?? 'reef_metrics_onchain';
-  const arg = `(record { ts = "${snapshot.ts}"; active = ${snapshot.active}; new_wallets = ${snapshot.new_wallets}; extrinsics = ${snapshot.extrinsics}; })`;
+  const signed = signSnapshot(snapshot);
+  const signature = signed
+    ? `opt record { publisher = "${escapeCandidText(signed.publisher)}"; signature = ${candidBlob(signed.signature)}; }`
+    : 'null';
+  const arg = `(record { ts = "${snapshot.ts}"; active = ${snapshot.active}; new_wallets = ${snapshot.new_wallets}; extrinsics = ${snapshot.extrinsics}; signature = ${signature}; }, opt record { idempotency_key = opt "${escapeCandidText(idempotencyKey)}"; })`;
 
   const repoRoot = path.resolve(path.dirname(fileURLToPath(import.meta.url)), '../..');
   const dfxProject = process.env.DFX_PROJECT_DIR ?? path.resolve(repoRoot, 'icp-onchain');
@@ -174,12 +179,12 @@ function ingestSnapshot(snapshot: {
   }
 }
 
-function ingestNewWalletsInflow(payload: string) {
+function ingestNewWalletsInflow(payload: string, idempotencyKey: string) {
   const network = process.env.DFX_NETWORK ?? 'ic';
   const identity = process.env.DFX_IDENTITY;
   const canister = process.env.ICP_CANISTER ?? 'reef_metrics_onchain';
   const escaped = escapeCandidText(payload);
-  const arg = `("${escaped}")`;
+  const arg = `("${escaped}", opt record { idempotency_key = opt "${escapeCandidText(idempotencyKey)}"; })`;
 
   const repoRoot = path.resolve(path.dirname(fileURLToPath(import.meta.url)), '../..');
   const dfxProject = process.env.DFX_PROJECT_DIR ?? path.resolve(repoRoot, 'icp-onchain');
@@ -285,8 +290,10 @@ async function run() {
 
   console.log('Snapshot:', snapshot);
   console.log(`New wallets with inflow >= min: ${entries.length}`);
-  ingestSnapshot(snapshot);
-  ingestNewWalletsInflow(inflowPayload);
+  // Keyed by content, so re-running the same day with unchanged values is deduplicated.
+  const contentHash = createHash('sha256').update(snapshotMessage(snapshot)).digest('hex');
+  ingestSnapshot(snapshot, `${snapshot.ts}:${contentHash.slice(0, 16)}`);
+  ingestNewWalletsInflow(inflowPayload, toIso);
   console.log('Snapshot pushed to ICP.');
 }
 
@@ -0,0 +1,41 @@
+/**
+ * Optional Ed25519 signing of ICP snapshots. The canister verifies the
+ * signature against a publisher registered via `add_publisher`.
+ */
+
+import { createPrivateKey, sign } from 'crypto';
+
+export interface IcpSnapshot {
+  ts: string;
+  active: number;
+  new_wallets: number;
+  extrinsics: number;
+}
+
+export interface IcpSnapshotSignature {
+  publisher: string;
+  signature: Buffer;
+}
+
+// Must match the canister's canonical form: keys sorted, no whitespace.
+// Pinned by `snapshot_message_matches_aggregator_signer` in the canister crate.
+export function snapshotMessage(snapshot: IcpSnapshot): Buffer {
+  return Buffer.from(
+    JSON.stringify({
+      active: snapshot.active,
+      extrinsics: snapshot.extrinsics,
+      new_wallets: snapshot.new_wallets,
+      ts: snapshot.ts,
+    })
+  );
+}
+
+// Signs with ICP_PUBLISHER_KEY (Ed25519 PKCS#8 PEM) when ICP_PUBLISHER_NAME is set.
+export function signSnapshot(snapshot: IcpSnapshot): IcpSnapshotSignature | null {
+  const publisher = process.env.ICP_PUBLISHER_NAME;
+  const keyPem = process.env.ICP_PUBLISHER_KEY;
+  if (!publisher || !keyPem) return null;
+
+  const key = createPrivateKey(keyPem.replace(/\\n/g, '\n'));
+  return { publisher, signature: sign(null, snapshotMessage(snapshot), key) };
+}
@@ -3,6 +3,7 @@ import cors from 'cors';
 import { initDb, getDb } from './db.js';
 import { runAggregation } from './cron.js';
 import { getStakingSummary } from './staking-summary.js';
+import { signSnapshot, IcpSnapshot } from './icp-signing.js';
 
 const app = express();
 const PORT = Number(process.env.AGGREGATOR_PORT || process.env.PORT || 3001);
@@ -39,6 +40,7 @@ app.get('/', (_req: Request, res: Response) => {
       'GET /v1/sparklines/extrinsics?hours=24',
       'GET /v1/sparklines/active-wallets?hours=24',
       'GET /v1/top-entities?limit=20',
+      'GET /v1/icp/snapshot',
     ],
   });
 });
@@ -206,6 +208,38 @@ app.get('/v1/sparklines/extrinsics-daily', (req: Request, res: Response) => {
   }
 });
 
+// GET /v1/icp/snapshot (pulled by the ICP canister when pull mode is enabled)
+// Daily snapshot only: the new-wallets inflow payload is computed by cron:icp
+// and still pushed through ingest_new_wallets_inflow.
+app.get('/v1/icp/snapshot', (_req: Request, res: Response) => {
+  try {
+    const rows = queryAll(
+      `SELECT ts_day, active_wallets, new_wallets, extrinsics_count FROM daily_buckets ORDER BY ts_day DESC LIMIT 1`
+    );
+    if (!rows.length) {
+      return res.status(404).json({ error: 'not_found', message: 'No daily snapshot yet' });
+    }
+
+    const row = rows[0];
+    const snapshot: IcpSnapshot = {
+      ts: row.ts_day as string,
+      active: row.active_wallets as number,
+      new_wallets: row.new_wallets as number,
+      extrinsics: row.extrinsics_count as number,
+    };
+    const signed = signSnapshot(snapshot);
+    res.json({
+      snapshot,
+      ...(signed && {
+        signature: { publisher: signed.publisher, signature: signed.signature.toString('base64') },
+      }),
+    });
+  } catch (err) {
+    console.error('Error in /v1/icp/snapshot:', err);
+    res.status(500).json({ error: 'internal', message: 'Failed to fetch snapshot' });
+  }
+});
+
 // GET /v1/top-entities
 app.get('/v1/top-entities', (req: Request, res: Response) => {
   try {
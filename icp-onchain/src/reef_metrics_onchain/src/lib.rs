@@ -1,33 +1,158 @@
 use base64::{engine::general_purpose, Engine as _};
 use candid::{CandidType, Deserialize, Principal};
+use futures::future::join_all;
 use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
 use ic_cdk::api::management_canister::http_request as ic_http;
 use ic_cdk::api::management_canister::http_request::{
     CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs, TransformContext,
 };
-use ic_cdk::api::{data_certificate, set_certified_data, time};
+use ic_cdk::api::{canister_balance128, data_certificate, set_certified_data, time};
 use ic_cdk::spawn;
-use ic_cdk_timers::set_timer_interval;
+use ic_cdk_timers::{clear_timer, set_timer, set_timer_interval, TimerId};
+use k256::ecdsa::signature::Verifier;
 use serde::Serialize;
 use serde_cbor::ser::Serializer;
 use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
 use std::cell::RefCell;
 use std::time::Duration;
-use time::{format_description::well_known::Rfc3339, Duration as TimeDuration, OffsetDateTime};
+use time::macros::format_description;
+use time::{
+    format_description::well_known::Rfc3339, Date, Duration as TimeDuration, OffsetDateTime,
+};
 
 const DEFAULT_SOURCE_URL: &str = "https://squid.subsquid.io/reef-explorer/graphql";
 const DEFAULT_DAYS: usize = 30;
-const MAX_PAGES: usize = 100;
-const HTTP_CYCLES: u128 = 50_000_000_000;
-const CERTIFIED_PATHS: [&str; 4] = [
-    "/",
-    "/active-wallets-daily.json",
-    "/extrinsics-daily.json",
-    "/new-wallets-inflow.json",
+const PAGES_PER_TICK: u32 = 32;
+const COLLECTION_WINDOWS: i64 = 24;
+const MAX_CONCURRENT_OUTCALLS: usize = 8;
+const COLLECTION_CONTINUE_SECS: u64 = 30;
+const COLLECTION_RETRY_BASE_SECS: u64 = 60;
+const HTTP_SUBNET_SIZE: u128 = 13;
+const TRANSFERS_MAX_RESPONSE_BYTES: u64 = 256_000;
+const COUNT_MAX_RESPONSE_BYTES: u64 = 16_000;
+const DEFAULT_DAILY_CYCLES_BUDGET: u128 = 1_000_000_000_000;
+const DEFAULT_MIN_CYCLES_BALANCE: u128 = 100_000_000_000;
+const CYCLES_SAMPLE_INTERVAL_SECS: u64 = 60 * 60;
+const CYCLES_HISTORY_LEN: usize = 14 * 24;
+const NANOS_PER_DAY: u128 = 24 * 60 * 60 * 1_000_000_000;
+const HEALTH_WARN_DAYS: f64 = 30.0;
+const HEALTH_CRITICAL_DAYS: f64 = 7.0;
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+const METRICS_PATH: &str = "/metrics";
+const LOG_CAPACITY: usize = 1_000;
+const DEFAULT_LOG_LIMIT: usize = 100;
+const INDEX_PATH: &str = "/";
+const META_PARAM: QueryParam = QueryParam {
+    name: "meta",
+    description: "Set to 1 for per-point provenance instead of the series. \
+                  Uncertified; use the raw domain.",
+};
+const ROUTES: [Route; 10] = [
+    Route {
+        path: INDEX_PATH,
+        content_type: "application/json",
+        description: "Index of all routes with last update times and body hashes.",
+        certified: true,
+        query: &[],
+        schema: index_schema,
+    },
+    Route {
+        path: "/openapi.json",
+        content_type: "application/json",
+        description: "OpenAPI 3 document generated from the route table.",
+        certified: true,
+        query: &[],
+        schema: object_schema,
+    },
+    Route {
+        path: "/active-wallets-daily.json",
+        content_type: "application/json",
+        description: "Daily active and new wallets for the retention window.",
+        certified: true,
+        query: &[META_PARAM],
+        schema: daily_schema,
+    },
+    Route {
+        path: "/extrinsics-daily.json",
+        content_type: "application/json",
+        description: "Daily extrinsic counts for the retention window.",
+        certified: true,
+        query: &[META_PARAM],
+        schema: extrinsics_schema,
+    },
+    Route {
+        path: "/new-wallets-inflow.json",
+        content_type: "application/json",
+        description: "New wallets of the last 24h with REEF inflow above the minimum.",
+        certified: true,
+        query: &[],
+        schema: inflow_schema,
+    },
+    Route {
+        path: "/health.json",
+        content_type: "application/json",
+        description: "Cycles balance, burn rate and days until freeze.",
+        certified: true,
+        query: &[],
+        schema: health_schema,
+    },
+    Route {
+        path: "/active-wallets-daily.csv",
+        content_type: "text/csv; charset=utf-8",
+        description: "CSV export of /active-wallets-daily.json.",
+        certified: true,
+        query: &[],
+        schema: text_schema,
+    },
+    Route {
+        path: "/extrinsics-daily.csv",
+        content_type: "text/csv; charset=utf-8",
+        description: "CSV export of /extrinsics-daily.json.",
+        certified: true,
+        query: &[],
+        schema: text_schema,
+    },
+    Route {
+        path: "/new-wallets-inflow.csv",
+        content_type: "text/csv; charset=utf-8",
+        description: "CSV export of /new-wallets-inflow.json.",
+        certified: true,
+        query: &[],
+        schema: text_schema,
+    },
+    Route {
+        path: METRICS_PATH,
+        content_type: "text/plain; version=0.0.4",
+        description: "Prometheus metrics. Uncertified; scrape via the raw domain.",
+        certified: false,
+        query: &[],
+        schema: text_schema,
+    },
 ];
 const CERT_LABEL: &[u8] = b"http_assets";
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+const METHOD_NOT_ALLOWED_BODY: &[u8] = b"Method not allowed";
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_WINDOW_BITS: u32 = 22;
+const TRANSFORM_METHOD: &str = "transform";
+const TRANSFORM_JSON_METHOD: &str = "transform_json";
+const AGGREGATOR_MAX_RESPONSE_BYTES: u64 = 64_000;
+const PULL_MAX_RESPONSE_BYTES: u64 = 256_000;
+const MAX_REVISIONS_PER_DAY: usize = 10;
+const MAX_JOB_ATTEMPTS: u32 = 5;
+const JOB_BUSY_RETRY_SECS: u64 = 5 * 60;
+const REFRESH_LOCK_TTL_SECS: u64 = 15 * 60;
+const FINISHED_JOBS_CAPACITY: usize = 200;
+const JOB_IDS_AFTER_MIGRATION: u64 = 1_000;
+const AUDIT_CAPACITY: usize = 500;
+const IDEMPOTENCY_WINDOW_SECS: u64 = 24 * 60 * 60;
+const MAX_IDEMPOTENCY_KEYS: usize = 256;
+const DEFAULT_CORS_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_PULL_INTERVAL_SECS: u64 = 4 * 60 * 60;
+const MIN_PULL_INTERVAL_SECS: u64 = 10 * 60;
 const TRANSFERS_PAGE_QUERY: &str = r#"
   query TransfersPage($from: DateTime!, $to: DateTime!, $after: String) {
     transfersConnection(
@@ -73,18 +198,29 @@ fn default_inflow_payload() -> String {
     json!({ "asOf": null, "minRaw": "0", "entries": [] }).to_string()
 }
 
+fn default_health_payload() -> String {
+    json!({ "status": "unknown", "cyclesBalance": null, "burnRatePerDay": null, "daysUntilFreeze": null })
+        .to_string()
+}
+
 #[derive(Clone, CandidType, Deserialize, Serialize)]
 struct DailyPoint {
     ts: String,
     active: u64,
     #[serde(rename = "new")]
     new_wallets: u64,
+    /// Publisher whose signature covered this point; `None` when unsigned or
+    /// collected by the canister itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    publisher: Option<String>,
 }
 
 #[derive(Clone, CandidType, Deserialize, Serialize)]
 struct DailyExtrinsicsPoint {
     ts: String,
     extrinsics: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    publisher: Option<String>,
 }
 
 #[derive(Clone, CandidType, Deserialize)]
@@ -93,6 +229,488 @@ struct DailySnapshotInput {
     active: u64,
     new_wallets: u64,
     extrinsics: u64,
+    signature: Option<SnapshotSignature>,
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, Serialize)]
+enum PointOrigin {
+    Refresh,
+    Ingest,
+    Pull,
+    Backfill,
+}
+
+/// Where a day's values came from. `source` is the GraphQL URL for
+/// refreshes, the pull URL for pulls and the caller principal for ingests.
+/// `complete` is false when new wallets could not be diffed against a
+/// previous wallet set.
+#[derive(Clone, CandidType, Deserialize, Serialize)]
+struct Provenance {
+    ts: String,
+    origin: PointOrigin,
+    source: String,
+    collected_at: u64,
+    pages: u32,
+    complete: bool,
+}
+
+/// A day's values as they were before being overwritten at `replaced_at`.
+/// `None` points mean the day did not exist yet.
+#[derive(Clone, CandidType, Deserialize)]
+struct PointRevision {
+    ts: String,
+    replaced_at: u64,
+    point: Option<DailyPoint>,
+    extrinsics: Option<DailyExtrinsicsPoint>,
+    provenance: Option<Provenance>,
+}
+
+/// Record of an admin data correction.
+#[derive(Clone, CandidType, Deserialize)]
+struct AuditEntry {
+    at: u64,
+    caller: Principal,
+    action: String,
+    details: String,
+}
+
+#[derive(CandidType)]
+struct PointHistoryEntry {
+    replaced_at: u64,
+    value: Option<u64>,
+    publisher: Option<String>,
+    provenance: Option<Provenance>,
+}
+
+/// Signature over the canonical snapshot JSON
+/// `{"active":..,"extrinsics":..,"new_wallets":..,"ts":".."}`.
+#[derive(Clone, CandidType, Deserialize)]
+struct SnapshotSignature {
+    publisher: String,
+    signature: Vec<u8>,
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, PartialEq, Eq)]
+enum SignatureScheme {
+    Ed25519,
+    Secp256k1,
+}
+
+/// A key allowed to sign snapshots. Secp256k1 keys are SEC1 encoded and sign
+/// the SHA-256 of the message (64-byte compact signatures).
+#[derive(Clone, CandidType, Deserialize)]
+struct Publisher {
+    name: String,
+    scheme: SignatureScheme,
+    public_key: Vec<u8>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct CyclesLedger {
+    daily_budget: u128,
+    min_balance: u128,
+    day: String,
+    spent_today: u128,
+    refresh_spent: u128,
+    total_spent: u128,
+}
+
+impl CyclesLedger {
+    fn new() -> Self {
+        Self {
+            daily_budget: DEFAULT_DAILY_CYCLES_BUDGET,
+            min_balance: DEFAULT_MIN_CYCLES_BALANCE,
+            day: String::new(),
+            spent_today: 0,
+            refresh_spent: 0,
+            total_spent: 0,
+        }
+    }
+
+    fn spent_on(&self, day: &str) -> u128 {
+        if self.day == day {
+            self.spent_today
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Serialize)]
+struct CyclesSample {
+    at: u64,
+    balance: u128,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
+        }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct LogEntry {
+    ts: u64,
+    level: LogLevel,
+    component: String,
+    message: String,
+    context: Vec<(String, String)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+enum Metric {
+    ActiveWallets,
+    NewWallets,
+    Extrinsics,
+}
+
+impl Metric {
+    fn as_str(self) -> &'static str {
+        match self {
+            Metric::ActiveWallets => "active_wallets",
+            Metric::NewWallets => "new_wallets",
+            Metric::Extrinsics => "extrinsics",
+        }
+    }
+
+    /// Field carrying this metric in the aggregator's daily series.
+    fn aggregator_field(self) -> &'static str {
+        match self {
+            Metric::ActiveWallets => "active",
+            Metric::NewWallets => "new",
+            Metric::Extrinsics => "extrinsics",
+        }
+    }
+}
+
+/// How a metric is obtained for a day. Anything other than `Paging` is tried
+/// first and falls back to paging transfer edges when it fails.
+#[derive(Clone, CandidType, Deserialize)]
+enum CollectionStrategy {
+    /// Page every transfer edge (active/new) or use `extrinsicsConnection.totalCount`.
+    Paging,
+    /// GraphQL query against the indexer taking `$from`/`$to`; the number is
+    /// read from `count_path` under `data`, e.g. `accountsConnection.totalCount`.
+    IndexerCount { query: String, count_path: String },
+    /// Our aggregator's daily endpoint, e.g. `/v1/sparklines/active-wallets-daily?days=2`,
+    /// returning `{ "series": [{ "ts": "YYYY-MM-DD", ... }] }`.
+    AggregatorDaily { url: String },
+}
+
+/// One `timestamp_gte/lt` slice of the collection window with its own cursor,
+/// so slices can be paged concurrently.
+#[derive(Clone, CandidType, Deserialize)]
+struct CollectionWindow {
+    from_iso: String,
+    to_iso: String,
+    after: Option<String>,
+    complete: bool,
+}
+
+/// Transfer pagination that outlives a single message: the cursors and the
+/// wallets seen so far are persisted after every round so a refresh can resume
+/// on the next tick instead of starting over. It is kept as an `Option` in
+/// `State`, so a shape change only drops an in-flight collection on upgrade.
+#[derive(Clone, CandidType, Deserialize)]
+struct PendingCollection {
+    day: String,
+    from_iso: String,
+    to_iso: String,
+    windows: Vec<CollectionWindow>,
+    aggregated_active: Option<u64>,
+    aggregated_new_wallets: Option<u64>,
+    aggregated_extrinsics: Option<u64>,
+    wallets: Vec<String>,
+    pages: u32,
+    started_at: u64,
+    attempts: u32,
+    last_error: Option<String>,
+    /// Aggregate strategies have been resolved and the budget reset.
+    prepared: bool,
+    /// Refetch of a past calendar day rather than the rolling last 24h.
+    backfill: bool,
+}
+
+impl PendingCollection {
+    fn for_last_24h() -> Result<Self, String> {
+        let now = current_time()?;
+        Self::for_range(now.date().to_string(), now - TimeDuration::hours(24), now, false)
+    }
+
+    /// Collects the UTC calendar day `day`.
+    fn for_day(day: &str) -> Result<Self, String> {
+        let start = parse_day(day)?.midnight().assume_utc();
+        Self::for_range(day.to_string(), start, start + TimeDuration::hours(24), true)
+    }
+
+    fn for_range(
+        day: String,
+        last_start: OffsetDateTime,
+        now: OffsetDateTime,
+        backfill: bool,
+    ) -> Result<Self, String> {
+        let to_iso = now
+            .format(&Rfc3339)
+            .map_err(|_| "failed to format to timestamp".to_string())?;
+        let from_iso = last_start
+            .format(&Rfc3339)
+            .map_err(|_| "failed to format last timestamp".to_string())?;
+        let mut windows = Vec::with_capacity(COLLECTION_WINDOWS as usize);
+        for index in 0..COLLECTION_WINDOWS {
+            let start = last_start + TimeDuration::hours(index);
+            let end = if index + 1 == COLLECTION_WINDOWS {
+                now
+            } else {
+                start + TimeDuration::hours(1)
+            };
+            windows.push(CollectionWindow {
+                from_iso: start
+                    .format(&Rfc3339)
+                    .map_err(|_| "failed to format window start".to_string())?,
+                to_iso: end
+                    .format(&Rfc3339)
+                    .map_err(|_| "failed to format window end".to_string())?,
+                after: None,
+                complete: false,
+            });
+        }
+        Ok(Self {
+            day,
+            from_iso,
+            to_iso,
+            windows,
+            aggregated_active: None,
+            aggregated_new_wallets: None,
+            aggregated_extrinsics: None,
+            wallets: Vec::new(),
+            pages: 0,
+            started_at: time(),
+            attempts: 0,
+            last_error: None,
+            prepared: false,
+            backfill,
+        })
+    }
+
+    fn is_complete(&self) -> bool {
+        self.windows.iter().all(|window| window.complete)
+    }
+}
+
+/// Progress of queued refetches (`backfill` / `backfill_gaps`). A new run
+/// starts once the previous one has finished or was cancelled.
+#[derive(Clone, CandidType, Deserialize)]
+struct BackfillProgress {
+    from_date: String,
+    to_date: String,
+    total: u32,
+    completed: u32,
+    started_at: u64,
+    finished_at: Option<u64>,
+    cancelled: bool,
+}
+
+#[derive(Clone, CandidType, Deserialize, PartialEq, Eq)]
+enum JobKind {
+    /// Rolling last-24h collection (continues an in-flight one).
+    Refresh,
+    /// Collection of one past UTC day.
+    Backfill { day: String },
+    Pull,
+    /// Rebuilds the derived series payloads and recertifies them.
+    Recertify,
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, PartialEq, Eq)]
+enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// Persisted unit of background work, run one at a time by the job timer.
+#[derive(Clone, CandidType, Deserialize)]
+struct Job {
+    id: u64,
+    kind: JobKind,
+    state: JobState,
+    attempts: u32,
+    next_run_at: u64,
+    created_at: u64,
+    updated_at: u64,
+    last_error: Option<String>,
+    result: Option<String>,
+}
+
+/// Held while a collection runs so concurrent refreshes cannot interleave
+/// their pages and clobber `prev_active_wallets`. A run that traps after an
+/// outcall never releases it, hence the expiry.
+#[derive(Clone, CandidType, Deserialize)]
+struct RefreshLock {
+    holder: String,
+    started_at: u64,
+}
+
+enum JobOutcome {
+    Done(String),
+    /// More work remains; run again after the delay without counting an attempt.
+    Continue(String, u64),
+}
+
+/// Jobs for refetch days queued before the job queue existed. Their ids stay
+/// below `JOB_IDS_AFTER_MIGRATION`.
+fn refetch_jobs(days: Vec<String>) -> Vec<Job> {
+    let now = time();
+    days.into_iter()
+        .zip(1..)
+        .map(|(day, id)| Job {
+            id,
+            kind: JobKind::Backfill { day },
+            state: JobState::Queued,
+            attempts: 0,
+            next_run_at: now,
+            created_at: now,
+            updated_at: now,
+            last_error: None,
+            result: None,
+        })
+        .collect()
+}
+
+#[derive(CandidType)]
+struct BackfillStatus {
+    progress: Option<BackfillProgress>,
+    current_day: Option<String>,
+    queued: Vec<String>,
+    /// Days of the current run whose jobs failed or were cancelled.
+    failed: Vec<String>,
+}
+
+enum RefreshOutcome {
+    /// Summary of the collected day.
+    Completed(String),
+    InProgress { day: String, pages: u32 },
+}
+
+#[derive(Clone, Default, CandidType, Deserialize)]
+struct Counters {
+    refresh_success: u64,
+    refresh_failure: u64,
+    last_refresh_at: Option<u64>,
+    outcalls: u64,
+    outcall_failures: u64,
+    outcall_request_bytes: u64,
+    outcall_response_bytes: u64,
+    ingest_snapshot_calls: u64,
+    ingest_inflow_calls: u64,
+}
+
+/// Bumped on every change so clients can pass `expected_version` back.
+/// `daily` covers the active/new wallets and extrinsics series, which are
+/// always written together.
+#[derive(Clone, Default, CandidType, Deserialize)]
+struct SeriesVersions {
+    daily: u64,
+    inflow: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct IdempotencyRecord {
+    key: String,
+    at: u64,
+    response: String,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+struct IngestOptions {
+    idempotency_key: Option<String>,
+    expected_version: Option<u64>,
+}
+
+/// Optional pull mode: the canister fetches the aggregator's snapshot
+/// envelope on its own timer instead of waiting for `ingest_daily_snapshot`.
+#[derive(Clone, CandidType, Deserialize)]
+struct PullConfig {
+    url: String,
+    enabled: bool,
+    interval_secs: u64,
+    last_pulled_at: Option<u64>,
+    last_snapshot_ts: Option<String>,
+    last_error: Option<String>,
+}
+
+impl PullConfig {
+    fn new() -> Self {
+        Self {
+            url: String::new(),
+            enabled: false,
+            interval_secs: DEFAULT_PULL_INTERVAL_SECS,
+            last_pulled_at: None,
+            last_snapshot_ts: None,
+            last_error: None,
+        }
+    }
+}
+
+/// CORS policy applied to every `http_request` response. `"*"` in
+/// `allowed_origins` allows any origin.
+#[derive(Clone, CandidType, Deserialize)]
+struct CorsConfig {
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<String>,
+    allowed_headers: Vec<String>,
+    max_age_secs: u64,
+}
+
+impl CorsConfig {
+    fn new() -> Self {
+        Self {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: ["GET", "HEAD", "OPTIONS"].map(String::from).to_vec(),
+            allowed_headers: ["Content-Type", "If-None-Match", "If-Modified-Since"]
+                .map(String::from)
+                .to_vec(),
+            max_age_secs: DEFAULT_CORS_MAX_AGE_SECS,
+        }
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == "*")
+    }
+
+    /// Value for `Access-Control-Allow-Origin`, if `origin` is allowed.
+    fn allow_origin(&self, origin: Option<&str>) -> Option<String> {
+        if self.allows_any_origin() {
+            return Some("*".to_string());
+        }
+        let origin = origin?;
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+            .then(|| origin.to_string())
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct PullConfigInput {
+    url: String,
+    enabled: bool,
+    interval_secs: u64,
 }
 
 #[derive(Clone, CandidType, Deserialize)]
@@ -107,6 +725,28 @@ struct State {
     extrinsics_series: Vec<DailyExtrinsicsPoint>,
     prev_active_wallets: Vec<String>,
     refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+    publishers: Vec<Publisher>,
+    require_signed_snapshots: bool,
+    versions: SeriesVersions,
+    idempotency_keys: Vec<IdempotencyRecord>,
+    provenance: Vec<Provenance>,
+    revisions: Vec<PointRevision>,
+    audit_log: Vec<AuditEntry>,
+    emit_gap_nulls: bool,
+    backfill_progress: Option<BackfillProgress>,
+    jobs: Vec<Job>,
+    next_job_id: u64,
+    refresh_lock: Option<RefreshLock>,
+    cors: CorsConfig,
 }
 
 impl State {
@@ -122,6 +762,28 @@ impl State {
             extrinsics_series: Vec::new(),
             prev_active_wallets: Vec::new(),
             refresh_enabled: true,
+            cycles: CyclesLedger::new(),
+            cycles_history: Vec::new(),
+            health_payload: default_health_payload(),
+            counters: Counters::default(),
+            logs: Vec::new(),
+            log_readers: Vec::new(),
+            pending_collection: None,
+            strategies: Vec::new(),
+            pull: PullConfig::new(),
+            publishers: Vec::new(),
+            require_signed_snapshots: false,
+            versions: SeriesVersions::default(),
+            idempotency_keys: Vec::new(),
+            provenance: Vec::new(),
+            revisions: Vec::new(),
+            audit_log: Vec::new(),
+            emit_gap_nulls: false,
+            backfill_progress: None,
+            jobs: Vec::new(),
+            next_job_id: 1,
+            refresh_lock: None,
+            cors: CorsConfig::new(),
         }
     }
 }
@@ -178,427 +840,3386 @@ struct StateV5 {
     refresh_enabled: bool,
 }
 
-impl From<StateV1> for State {
-    fn from(state: StateV1) -> Self {
-        Self {
-            owner: state.owner,
-            source_url: state.source_url,
-            payload: state.payload,
-            extrinsics_payload: default_extrinsics_payload(),
-            inflow_payload: default_inflow_payload(),
-            last_updated: state.last_updated,
-            series: Vec::new(),
-            extrinsics_series: Vec::new(),
-            prev_active_wallets: Vec::new(),
-            refresh_enabled: true,
-        }
-    }
-}
-
-impl From<StateV2> for State {
-    fn from(state: StateV2) -> Self {
-        Self {
-            owner: state.owner,
-            source_url: state.source_url,
-            payload: state.payload,
-            extrinsics_payload: default_extrinsics_payload(),
-            inflow_payload: default_inflow_payload(),
-            last_updated: state.last_updated,
-            series: state.series,
-            extrinsics_series: Vec::new(),
-            prev_active_wallets: Vec::new(),
-            refresh_enabled: true,
-        }
-    }
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV6 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
 }
 
-impl From<StateV3> for State {
-    fn from(state: StateV3) -> Self {
-        Self {
-            owner: state.owner,
-            source_url: state.source_url,
-            payload: state.payload,
-            extrinsics_payload: default_extrinsics_payload(),
-            inflow_payload: default_inflow_payload(),
-            last_updated: state.last_updated,
-            series: state.series,
-            extrinsics_series: Vec::new(),
-            prev_active_wallets: state.prev_active_wallets,
-            refresh_enabled: true,
-        }
-    }
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV7 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
 }
 
-impl From<StateV4> for State {
-    fn from(state: StateV4) -> Self {
-        Self {
-            owner: state.owner,
-            source_url: state.source_url,
-            payload: state.payload,
-            extrinsics_payload: state.extrinsics_payload,
-            inflow_payload: default_inflow_payload(),
-            last_updated: state.last_updated,
-            series: state.series,
-            extrinsics_series: state.extrinsics_series,
-            prev_active_wallets: state.prev_active_wallets,
-            refresh_enabled: true,
-        }
-    }
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV8 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
 }
 
-impl From<StateV5> for State {
-    fn from(state: StateV5) -> Self {
-        Self {
-            owner: state.owner,
-            source_url: state.source_url,
-            payload: state.payload,
-            extrinsics_payload: state.extrinsics_payload,
-            inflow_payload: default_inflow_payload(),
-            last_updated: state.last_updated,
-            series: state.series,
-            extrinsics_series: state.extrinsics_series,
-            prev_active_wallets: state.prev_active_wallets,
-            refresh_enabled: state.refresh_enabled,
-        }
-    }
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV9 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
 }
 
-thread_local! {
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV10 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV11 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV12 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV13 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV14 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+    publishers: Vec<Publisher>,
+    require_signed_snapshots: bool,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV15 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+    publishers: Vec<Publisher>,
+    require_signed_snapshots: bool,
+    versions: SeriesVersions,
+    idempotency_keys: Vec<IdempotencyRecord>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV16 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+    publishers: Vec<Publisher>,
+    require_signed_snapshots: bool,
+    versions: SeriesVersions,
+    idempotency_keys: Vec<IdempotencyRecord>,
+    provenance: Vec<Provenance>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV17 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+    publishers: Vec<Publisher>,
+    require_signed_snapshots: bool,
+    versions: SeriesVersions,
+    idempotency_keys: Vec<IdempotencyRecord>,
+    provenance: Vec<Provenance>,
+    revisions: Vec<PointRevision>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV18 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+    publishers: Vec<Publisher>,
+    require_signed_snapshots: bool,
+    versions: SeriesVersions,
+    idempotency_keys: Vec<IdempotencyRecord>,
+    provenance: Vec<Provenance>,
+    revisions: Vec<PointRevision>,
+    audit_log: Vec<AuditEntry>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV19 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+    publishers: Vec<Publisher>,
+    require_signed_snapshots: bool,
+    versions: SeriesVersions,
+    idempotency_keys: Vec<IdempotencyRecord>,
+    provenance: Vec<Provenance>,
+    revisions: Vec<PointRevision>,
+    audit_log: Vec<AuditEntry>,
+    emit_gap_nulls: bool,
+    refetch_days: Vec<String>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV20 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+    publishers: Vec<Publisher>,
+    require_signed_snapshots: bool,
+    versions: SeriesVersions,
+    idempotency_keys: Vec<IdempotencyRecord>,
+    provenance: Vec<Provenance>,
+    revisions: Vec<PointRevision>,
+    audit_log: Vec<AuditEntry>,
+    emit_gap_nulls: bool,
+    refetch_days: Vec<String>,
+    backfill_progress: Option<BackfillProgress>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV21 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+    publishers: Vec<Publisher>,
+    require_signed_snapshots: bool,
+    versions: SeriesVersions,
+    idempotency_keys: Vec<IdempotencyRecord>,
+    provenance: Vec<Provenance>,
+    revisions: Vec<PointRevision>,
+    audit_log: Vec<AuditEntry>,
+    emit_gap_nulls: bool,
+    backfill_progress: Option<BackfillProgress>,
+    jobs: Vec<Job>,
+    next_job_id: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StateV22 {
+    owner: Principal,
+    source_url: String,
+    payload: String,
+    extrinsics_payload: String,
+    inflow_payload: String,
+    last_updated: Option<u64>,
+    series: Vec<DailyPoint>,
+    extrinsics_series: Vec<DailyExtrinsicsPoint>,
+    prev_active_wallets: Vec<String>,
+    refresh_enabled: bool,
+    cycles: CyclesLedger,
+    cycles_history: Vec<CyclesSample>,
+    health_payload: String,
+    counters: Counters,
+    logs: Vec<LogEntry>,
+    log_readers: Vec<Principal>,
+    pending_collection: Option<PendingCollection>,
+    strategies: Vec<(Metric, CollectionStrategy)>,
+    pull: PullConfig,
+    publishers: Vec<Publisher>,
+    require_signed_snapshots: bool,
+    versions: SeriesVersions,
+    idempotency_keys: Vec<IdempotencyRecord>,
+    provenance: Vec<Provenance>,
+    revisions: Vec<PointRevision>,
+    audit_log: Vec<AuditEntry>,
+    emit_gap_nulls: bool,
+    backfill_progress: Option<BackfillProgress>,
+    jobs: Vec<Job>,
+    next_job_id: u64,
+    refresh_lock: Option<RefreshLock>,
+}
+
+impl From<StateV1> for State {
+    fn from(state: StateV1) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            last_updated: state.last_updated,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV2> for State {
+    fn from(state: StateV2) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV3> for State {
+    fn from(state: StateV3) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            prev_active_wallets: state.prev_active_wallets,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV4> for State {
+    fn from(state: StateV4) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV5> for State {
+    fn from(state: StateV5) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV6> for State {
+    fn from(state: StateV6) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV7> for State {
+    fn from(state: StateV7) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV8> for State {
+    fn from(state: StateV8) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV9> for State {
+    fn from(state: StateV9) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV10> for State {
+    fn from(state: StateV10) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV11> for State {
+    fn from(state: StateV11) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV12> for State {
+    fn from(state: StateV12) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV13> for State {
+    fn from(state: StateV13) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            pull: state.pull,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV14> for State {
+    fn from(state: StateV14) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            pull: state.pull,
+            publishers: state.publishers,
+            require_signed_snapshots: state.require_signed_snapshots,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV15> for State {
+    fn from(state: StateV15) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            pull: state.pull,
+            publishers: state.publishers,
+            require_signed_snapshots: state.require_signed_snapshots,
+            versions: state.versions,
+            idempotency_keys: state.idempotency_keys,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV16> for State {
+    fn from(state: StateV16) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            pull: state.pull,
+            publishers: state.publishers,
+            require_signed_snapshots: state.require_signed_snapshots,
+            versions: state.versions,
+            idempotency_keys: state.idempotency_keys,
+            provenance: state.provenance,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV17> for State {
+    fn from(state: StateV17) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            pull: state.pull,
+            publishers: state.publishers,
+            require_signed_snapshots: state.require_signed_snapshots,
+            versions: state.versions,
+            idempotency_keys: state.idempotency_keys,
+            provenance: state.provenance,
+            revisions: state.revisions,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV18> for State {
+    fn from(state: StateV18) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            pull: state.pull,
+            publishers: state.publishers,
+            require_signed_snapshots: state.require_signed_snapshots,
+            versions: state.versions,
+            idempotency_keys: state.idempotency_keys,
+            provenance: state.provenance,
+            revisions: state.revisions,
+            audit_log: state.audit_log,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV19> for State {
+    fn from(state: StateV19) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            pull: state.pull,
+            publishers: state.publishers,
+            require_signed_snapshots: state.require_signed_snapshots,
+            versions: state.versions,
+            idempotency_keys: state.idempotency_keys,
+            provenance: state.provenance,
+            revisions: state.revisions,
+            audit_log: state.audit_log,
+            emit_gap_nulls: state.emit_gap_nulls,
+            jobs: refetch_jobs(state.refetch_days),
+            next_job_id: JOB_IDS_AFTER_MIGRATION,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV20> for State {
+    fn from(state: StateV20) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            pull: state.pull,
+            publishers: state.publishers,
+            require_signed_snapshots: state.require_signed_snapshots,
+            versions: state.versions,
+            idempotency_keys: state.idempotency_keys,
+            provenance: state.provenance,
+            revisions: state.revisions,
+            audit_log: state.audit_log,
+            emit_gap_nulls: state.emit_gap_nulls,
+            jobs: refetch_jobs(state.refetch_days),
+            next_job_id: JOB_IDS_AFTER_MIGRATION,
+            backfill_progress: state.backfill_progress,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV21> for State {
+    fn from(state: StateV21) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            pull: state.pull,
+            publishers: state.publishers,
+            require_signed_snapshots: state.require_signed_snapshots,
+            versions: state.versions,
+            idempotency_keys: state.idempotency_keys,
+            provenance: state.provenance,
+            revisions: state.revisions,
+            audit_log: state.audit_log,
+            emit_gap_nulls: state.emit_gap_nulls,
+            backfill_progress: state.backfill_progress,
+            jobs: state.jobs,
+            next_job_id: state.next_job_id,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+impl From<StateV22> for State {
+    fn from(state: StateV22) -> Self {
+        Self {
+            source_url: state.source_url,
+            payload: state.payload,
+            extrinsics_payload: state.extrinsics_payload,
+            inflow_payload: state.inflow_payload,
+            last_updated: state.last_updated,
+            series: state.series,
+            extrinsics_series: state.extrinsics_series,
+            prev_active_wallets: state.prev_active_wallets,
+            refresh_enabled: state.refresh_enabled,
+            cycles: state.cycles,
+            cycles_history: state.cycles_history,
+            health_payload: state.health_payload,
+            counters: state.counters,
+            logs: state.logs,
+            log_readers: state.log_readers,
+            pending_collection: state.pending_collection,
+            strategies: state.strategies,
+            pull: state.pull,
+            publishers: state.publishers,
+            require_signed_snapshots: state.require_signed_snapshots,
+            versions: state.versions,
+            idempotency_keys: state.idempotency_keys,
+            provenance: state.provenance,
+            revisions: state.revisions,
+            audit_log: state.audit_log,
+            emit_gap_nulls: state.emit_gap_nulls,
+            backfill_progress: state.backfill_progress,
+            jobs: state.jobs,
+            next_job_id: state.next_job_id,
+            refresh_lock: state.refresh_lock,
+            ..Self::new(state.owner)
+        }
+    }
+}
+
+thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::new(Principal::anonymous()));
-    static CERT_TREE: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::new());
+    static PULL_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    static JOB_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    static CERT_TREE: RefCell<RbTree<String, Hash>> = const { RefCell::new(RbTree::new()) };
+    static ENCODED_BODIES: RefCell<HashMap<String, EncodedBodies>> = RefCell::new(HashMap::new());
+}
+
+/// One entry of the HTTP surface. The table drives routing, certification,
+/// the `/` index and `/openapi.json`.
+struct Route {
+    path: &'static str,
+    content_type: &'static str,
+    description: &'static str,
+    certified: bool,
+    query: &'static [QueryParam],
+    schema: fn() -> Value,
+}
+
+struct QueryParam {
+    name: &'static str,
+    description: &'static str,
+}
+
+/// Compressed variants of a certified body, rebuilt whenever the body changes.
+/// Derived data, so it lives outside `State`.
+struct EncodedBodies {
+    gzip: Vec<u8>,
+    brotli: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Json,
+    Csv,
+    Cbor,
+}
+
+impl Format {
+    fn media_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            _ => self.media_type(),
+        }
+    }
+}
+
+/// A route body in one format. `key` is the route path for JSON and CSV and
+/// `<json path>#cbor` for CBOR, which is built per request and uncertified.
+struct Representation {
+    format: Format,
+    key: String,
+}
+
+#[derive(Clone, Copy)]
+enum ContentEncoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CanisterHttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CanisterHttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Status {
+    source_url: String,
+    last_updated: Option<u64>,
+    payload_bytes: u64,
+    refresh_enabled: bool,
+    cycles_balance: u128,
+    cycles_spent_today: u128,
+    last_refresh_cycles: u128,
+    daily_cycles_budget: u128,
+    min_cycles_balance: u128,
+    pending_collection: Option<CollectionStatus>,
+    daily_version: u64,
+    inflow_version: u64,
+    gaps: Vec<String>,
+    refetch_queue: Vec<String>,
+    refresh_lock: Option<RefreshLock>,
+    pull_url: String,
+    pull_enabled: bool,
+    last_pulled_at: Option<u64>,
+    last_pull_error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CollectionStatus {
+    day: String,
+    pages: u32,
+    wallets: u64,
+    windows_done: u32,
+    windows_total: u32,
+    started_at: u64,
+    attempts: u32,
+    last_error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CyclesReport {
+    balance: u128,
+    burn_rate_per_day: Option<u128>,
+    estimated_days_until_freeze: Option<f64>,
+    spent_today: u128,
+    daily_budget: u128,
+    min_balance: u128,
+    history: Vec<CyclesSample>,
+}
+
+#[ic_cdk::init]
+fn init() {
+    let owner = ic_cdk::caller();
+    STATE.with(|state| {
+        *state.borrow_mut() = State::new(owner);
+    });
+
+    sample_cycles();
+    init_timers();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    let restored = ic_cdk::storage::stable_restore::<(State,)>().ok();
+    if let Some((state,)) = restored {
+        STATE.with(|s| *s.borrow_mut() = state);
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV22,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV21,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV20,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV19,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV18,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV17,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV16,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV15,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV14,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV13,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV12,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV11,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV10,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV9,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV8,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV7,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV6,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV5,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV4,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV3,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV2,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV1,)>() {
+        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
+    } else {
+        let owner = ic_cdk::caller();
+        STATE.with(|state| *state.borrow_mut() = State::new(owner));
+    }
+
+    sample_cycles();
+    init_timers();
+    log(LogLevel::Info, "lifecycle", "post_upgrade completed", &[]);
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    STATE.with(|state| {
+        let _ = ic_cdk::storage::stable_save((state.borrow().clone(),));
+    });
+}
+
+fn init_timers() {
+    set_timer_interval(Duration::from_secs(24 * 60 * 60), || {
+        enqueue_job(JobKind::Refresh, 0);
+        if STATE.with(|state| state.borrow().emit_gap_nulls) {
+            enqueue_job(JobKind::Recertify, 0);
+        }
+    });
+    set_timer_interval(Duration::from_secs(CYCLES_SAMPLE_INTERVAL_SECS), sample_cycles);
+    // Watchdog: a trapped job blocks the queue until `schedule_jobs` requeues it.
+    set_timer_interval(Duration::from_secs(REFRESH_LOCK_TTL_SECS), schedule_jobs);
+    schedule_pull_timer();
+    // A job or refresh that was running when the canister was upgraded never
+    // finished.
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.refresh_lock = None;
+        for job in state.jobs.iter_mut() {
+            if job.state == JobState::Running {
+                job.state = JobState::Queued;
+            }
+        }
+    });
+    schedule_jobs();
+}
+
+/// (Re)registers the pull timer so interval changes take effect immediately.
+fn schedule_pull_timer() {
+    let (enabled, interval_secs) = STATE.with(|state| {
+        let pull = &state.borrow().pull;
+        (pull.enabled && !pull.url.is_empty(), pull.interval_secs)
+    });
+    PULL_TIMER.with(|timer| {
+        if let Some(timer_id) = timer.borrow_mut().take() {
+            clear_timer(timer_id);
+        }
+        if enabled {
+            let timer_id = set_timer_interval(Duration::from_secs(interval_secs), || {
+                enqueue_job(JobKind::Pull, 0);
+            });
+            *timer.borrow_mut() = Some(timer_id);
+        }
+    });
+}
+
+/// Records the current balance, rebuilds `/health.json` and recertifies.
+/// Runs on init/upgrade and hourly afterwards.
+fn sample_cycles() {
+    let balance = canister_balance128();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.cycles_history.push(CyclesSample { at: time(), balance });
+        if state.cycles_history.len() > CYCLES_HISTORY_LEN {
+            let excess = state.cycles_history.len() - CYCLES_HISTORY_LEN;
+            state.cycles_history.drain(0..excess);
+        }
+        state.health_payload = build_health_payload(&state, balance);
+    });
+    update_certified_data();
+}
+
+fn assert_owner() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = STATE.with(|state| state.borrow().owner);
+    if caller == owner {
+        Ok(())
+    } else {
+        Err("unauthorized".to_string())
+    }
+}
+
+fn assert_log_reader() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let allowed = STATE.with(|state| {
+        let state = state.borrow();
+        caller == state.owner || state.log_readers.contains(&caller)
+    });
+    if allowed {
+        Ok(())
+    } else {
+        Err("unauthorized".to_string())
+    }
 }
 
-#[derive(CandidType, Deserialize)]
-struct CanisterHttpRequest {
-    method: String,
-    url: String,
-    headers: Vec<(String, String)>,
-    body: Vec<u8>,
+/// Appends to the bounded log buffer and mirrors the line to the replica
+/// console for local debugging.
+fn log(level: LogLevel, component: &str, message: &str, context: &[(&str, String)]) {
+    let context: Vec<(String, String)> = context
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.clone()))
+        .collect();
+    let rendered: Vec<String> = context
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+    ic_cdk::println!("[{}] {component}: {message} {}", level.as_str(), rendered.join(" "));
+    STATE.with(|state| {
+        let logs = &mut state.borrow_mut().logs;
+        logs.push(LogEntry {
+            ts: time(),
+            level,
+            component: component.to_string(),
+            message: message.to_string(),
+            context,
+        });
+        if logs.len() > LOG_CAPACITY {
+            let excess = logs.len() - LOG_CAPACITY;
+            logs.drain(0..excess);
+        }
+    });
+}
+
+async fn refresh_internal() -> Result<RefreshOutcome, String> {
+    let outcome = run_refresh().await;
+    match &outcome {
+        Ok(RefreshOutcome::Completed(_)) => {
+            STATE.with(|state| {
+                let counters = &mut state.borrow_mut().counters;
+                counters.refresh_success += 1;
+                counters.last_refresh_at = Some(time());
+            });
+            log(LogLevel::Info, "refresh", "refresh completed", &[]);
+        }
+        Ok(RefreshOutcome::InProgress { day, pages }) => {
+            log(
+                LogLevel::Info,
+                "refresh",
+                "collection continues on next tick",
+                &[("day", day.clone()), ("pages", pages.to_string())],
+            );
+        }
+        Err(err) => {
+            STATE.with(|state| state.borrow_mut().counters.refresh_failure += 1);
+            log(LogLevel::Error, "refresh", "refresh failed", &[("error", err.clone())]);
+        }
+    }
+    outcome
+}
+
+/// Queues a job to run after `delay_secs`. A queued job of the same kind is
+/// reused instead, so timers firing while work is pending do not pile up.
+fn enqueue_job(kind: JobKind, delay_secs: u64) -> u64 {
+    let now = time();
+    let id = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(job) = state
+            .jobs
+            .iter()
+            .find(|job| job.state == JobState::Queued && job.kind == kind)
+        {
+            return job.id;
+        }
+        let id = state.next_job_id;
+        state.next_job_id += 1;
+        state.jobs.push(Job {
+            id,
+            kind,
+            state: JobState::Queued,
+            attempts: 0,
+            next_run_at: now + delay_secs * 1_000_000_000,
+            created_at: now,
+            updated_at: now,
+            last_error: None,
+            result: None,
+        });
+        id
+    });
+    schedule_jobs();
+    id
+}
+
+/// Arms the job timer for the earliest queued job. Only one job runs at a
+/// time; a finishing job re-arms the timer.
+fn schedule_jobs() {
+    let now = time();
+    let next_run_at = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        // A job whose callback trapped stays `Running` forever; give it back
+        // once it outlives the refresh lock it would have held.
+        let stall_cutoff = now.saturating_sub(REFRESH_LOCK_TTL_SECS * 1_000_000_000);
+        for job in state.jobs.iter_mut() {
+            if job.state == JobState::Running && job.updated_at < stall_cutoff {
+                job.state = JobState::Queued;
+                job.attempts += 1;
+                job.last_error = Some("job stalled".to_string());
+            }
+        }
+        if state.jobs.iter().any(|job| job.state == JobState::Running) {
+            return None;
+        }
+        state
+            .jobs
+            .iter()
+            .filter(|job| job.state == JobState::Queued)
+            .map(|job| job.next_run_at)
+            .min()
+    });
+    JOB_TIMER.with(|timer| {
+        if let Some(timer_id) = timer.borrow_mut().take() {
+            clear_timer(timer_id);
+        }
+        if let Some(next_run_at) = next_run_at {
+            let delay = Duration::from_nanos(next_run_at.saturating_sub(now));
+            *timer.borrow_mut() = Some(set_timer(delay, run_next_job));
+        }
+    });
+}
+
+fn run_next_job() {
+    let now = time();
+    let job = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let job = state
+            .jobs
+            .iter_mut()
+            .filter(|job| job.state == JobState::Queued && job.next_run_at <= now)
+            .min_by_key(|job| (job.next_run_at, job.id))?;
+        job.state = JobState::Running;
+        job.updated_at = now;
+        Some((job.id, job.kind.clone()))
+    });
+    let Some((id, kind)) = job else {
+        schedule_jobs();
+        return;
+    };
+    spawn(async move {
+        let outcome = execute_job(id, &kind).await;
+        finish_job(id, outcome);
+        schedule_jobs();
+    });
+}
+
+async fn execute_job(id: u64, kind: &JobKind) -> Result<JobOutcome, String> {
+    match kind {
+        JobKind::Refresh => run_collection_job(id, None).await,
+        JobKind::Backfill { day } => run_collection_job(id, Some(day)).await,
+        JobKind::Pull => pull_snapshot().await.map(JobOutcome::Done),
+        JobKind::Recertify => {
+            STATE.with(|state| build_series_payloads(&mut state.borrow_mut()));
+            update_certified_data();
+            Ok(JobOutcome::Done("recertified".to_string()))
+        }
+    }
+}
+
+/// Runs the rolling refresh (`day` is `None`) or a backfill day under the
+/// refresh lock. Concurrent requests are coalesced by deferring the job.
+async fn run_collection_job(id: u64, day: Option<&String>) -> Result<JobOutcome, String> {
+    if let Err(locked) = acquire_refresh_lock(format!("job {id}")) {
+        return Ok(JobOutcome::Continue(locked, JOB_BUSY_RETRY_SECS));
+    }
+    if collection_busy(day) {
+        release_refresh_lock();
+        let waiting = if day.is_some() { "collection" } else { "backfill" };
+        return Ok(JobOutcome::Continue(format!("waiting for {waiting}"), JOB_BUSY_RETRY_SECS));
+    }
+    if let Some(day) = day {
+        let pending = match PendingCollection::for_day(day) {
+            Ok(pending) => pending,
+            Err(err) => {
+                release_refresh_lock();
+                return Err(err);
+            }
+        };
+        STATE.with(|state| {
+            state.borrow_mut().pending_collection.get_or_insert(pending);
+        });
+    }
+    let outcome = refresh_internal().await;
+    release_refresh_lock();
+    collection_outcome(outcome?)
+}
+
+/// Takes the refresh lock, replacing an expired one. Fails with a description
+/// of the current holder otherwise.
+fn acquire_refresh_lock(holder: String) -> Result<(), String> {
+    let now = time();
+    let ttl = REFRESH_LOCK_TTL_SECS * 1_000_000_000;
+    let expired = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let expired = match &state.refresh_lock {
+            Some(lock) if now.saturating_sub(lock.started_at) < ttl => {
+                return Err(format!(
+                    "refresh already running ({} since {})",
+                    lock.holder, lock.started_at
+                ));
+            }
+            Some(lock) => Some(lock.holder.clone()),
+            None => None,
+        };
+        state.refresh_lock = Some(RefreshLock {
+            holder,
+            started_at: now,
+        });
+        Ok(expired)
+    })?;
+    if let Some(stale) = expired {
+        log(LogLevel::Warn, "refresh", "expired refresh lock taken over", &[("holder", stale)]);
+    }
+    Ok(())
+}
+
+fn release_refresh_lock() {
+    STATE.with(|state| state.borrow_mut().refresh_lock = None);
+}
+
+/// Whether the single collection slot holds work other than the requested
+/// backfill day (`None` for the rolling refresh).
+fn collection_busy(day: Option<&String>) -> bool {
+    STATE.with(|state| match (&state.borrow().pending_collection, day) {
+        (None, _) => false,
+        (Some(pending), Some(day)) => !(pending.backfill && &pending.day == day),
+        (Some(pending), None) => pending.backfill,
+    })
+}
+
+fn collection_outcome(outcome: RefreshOutcome) -> Result<JobOutcome, String> {
+    Ok(match outcome {
+        RefreshOutcome::Completed(summary) => JobOutcome::Done(summary),
+        RefreshOutcome::InProgress { day, pages } => JobOutcome::Continue(
+            format!("collection for {day} in progress ({pages} pages)"),
+            COLLECTION_CONTINUE_SECS,
+        ),
+    })
+}
+
+fn finish_job(id: u64, outcome: Result<JobOutcome, String>) {
+    let now = time();
+    STATE.with(|state| apply_job_outcome(&mut state.borrow_mut(), id, outcome, now));
+}
+
+/// Records a job's outcome: success, a deferred continuation, or a failure
+/// retried with backoff until `MAX_JOB_ATTEMPTS`. A backfill day given up on
+/// frees its collection slot and still counts towards its run. Jobs cancelled
+/// while running stay cancelled.
+fn apply_job_outcome(state: &mut State, id: u64, outcome: Result<JobOutcome, String>, now: u64) {
+    let Some(job) = state.jobs.iter_mut().find(|job| job.id == id) else {
+        return;
+    };
+    if job.state == JobState::Cancelled {
+        return;
+    }
+    job.updated_at = now;
+    match outcome {
+        Ok(JobOutcome::Done(result)) => {
+            job.state = JobState::Succeeded;
+            job.result = Some(result);
+            job.last_error = None;
+        }
+        Ok(JobOutcome::Continue(result, delay_secs)) => {
+            job.state = JobState::Queued;
+            job.result = Some(result);
+            job.attempts = 0;
+            job.next_run_at = now + delay_secs * 1_000_000_000;
+        }
+        Err(err) => {
+            job.attempts += 1;
+            job.last_error = Some(err);
+            if job.attempts < MAX_JOB_ATTEMPTS {
+                job.state = JobState::Queued;
+                job.next_run_at =
+                    now + (COLLECTION_RETRY_BASE_SECS << job.attempts) * 1_000_000_000;
+            } else {
+                job.state = JobState::Failed;
+            }
+        }
+    }
+    let abandoned = match (&job.kind, job.state) {
+        (JobKind::Backfill { day }, JobState::Failed) => Some(day.clone()),
+        _ => None,
+    };
+    if let Some(day) = abandoned {
+        release_backfill_collection(state, &day);
+        complete_refetch(state, now);
+    }
+    prune_finished_jobs(&mut state.jobs);
+}
+
+/// Frees the collection slot held by an abandoned backfill day so other jobs
+/// are not deferred forever.
+fn release_backfill_collection(state: &mut State, day: &str) {
+    if state
+        .pending_collection
+        .as_ref()
+        .is_some_and(|pending| pending.backfill && pending.day == day)
+    {
+        state.pending_collection = None;
+    }
+}
+
+fn prune_finished_jobs(jobs: &mut Vec<Job>) {
+    let finished = |job: &Job| !matches!(job.state, JobState::Queued | JobState::Running);
+    let mut excess = jobs.iter().filter(|job| finished(job)).count();
+    excess = excess.saturating_sub(FINISHED_JOBS_CAPACITY);
+    jobs.retain(|job| {
+        if excess > 0 && finished(job) {
+            excess -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+fn save_pending_collection(pending: &PendingCollection) {
+    STATE.with(|state| state.borrow_mut().pending_collection = Some(pending.clone()));
+}
+
+/// Fails once `cancel_backfill` cancelled the job owning this backfill
+/// collection while an outcall was in flight, so its results are neither
+/// saved nor applied.
+fn ensure_backfill_active(pending: &PendingCollection) -> Result<(), String> {
+    let active = !pending.backfill
+        || STATE.with(|state| {
+            state.borrow().jobs.iter().any(|job| {
+                job.state == JobState::Running
+                    && matches!(&job.kind, JobKind::Backfill { day } if *day == pending.day)
+            })
+        });
+    if active {
+        Ok(())
+    } else {
+        Err("backfill cancelled".to_string())
+    }
+}
+
+/// Records a failed page on the collection; the owning job retries it with
+/// backoff.
+fn fail_pending_collection(pending: &mut PendingCollection, err: &str) {
+    pending.attempts += 1;
+    pending.last_error = Some(err.to_string());
+    save_pending_collection(pending);
+}
+
+async fn run_refresh() -> Result<RefreshOutcome, String> {
+    let (graphql_url, refresh_enabled, pending, strategies) = STATE.with(|state| {
+        let state = state.borrow();
+        (
+            state.source_url.clone(),
+            state.refresh_enabled,
+            state.pending_collection.clone(),
+            state.strategies.clone(),
+        )
+    });
+    if !refresh_enabled {
+        return Ok(RefreshOutcome::Completed("refresh disabled".to_string()));
+    }
+    reserve_outcall_cycles(0)?;
+    let mut pending = match pending {
+        Some(pending) => pending,
+        None => PendingCollection::for_last_24h()?,
+    };
+    if !pending.prepared {
+        STATE.with(|state| state.borrow_mut().cycles.refresh_spent = 0);
+        resolve_aggregates(&graphql_url, &strategies, &mut pending).await;
+        ensure_backfill_active(&pending)?;
+        if pending.aggregated_active.is_some() && pending.aggregated_new_wallets.is_some() {
+            pending.windows.clear();
+        }
+        pending.prepared = true;
+        save_pending_collection(&pending);
+    }
+
+    let mut seen: HashSet<String> = pending.wallets.iter().cloned().collect();
+    let mut tick_pages = 0;
+    while !pending.is_complete() {
+        if tick_pages >= PAGES_PER_TICK {
+            return Ok(RefreshOutcome::InProgress {
+                day: pending.day,
+                pages: pending.pages,
+            });
+        }
+        let batch: Vec<usize> = pending
+            .windows
+            .iter()
+            .enumerate()
+            .filter(|(_, window)| !window.complete)
+            .map(|(index, _)| index)
+            .take(MAX_CONCURRENT_OUTCALLS)
+            .collect();
+        let results = join_all(batch.iter().map(|&index| {
+            let window = &pending.windows[index];
+            fetch_transfers_page(
+                &graphql_url,
+                &window.from_iso,
+                &window.to_iso,
+                window.after.clone(),
+            )
+        }))
+        .await;
+        ensure_backfill_active(&pending)?;
+
+        let mut first_error = None;
+        for (index, result) in batch.into_iter().zip(results) {
+            match result {
+                Ok((wallets, next_cursor)) => {
+                    for wallet in wallets {
+                        if seen.insert(wallet.clone()) {
+                            pending.wallets.push(wallet);
+                        }
+                    }
+                    let window = &mut pending.windows[index];
+                    window.complete = next_cursor.is_none();
+                    window.after = next_cursor;
+                    pending.pages += 1;
+                    tick_pages += 1;
+                }
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+        if let Some(err) = first_error {
+            fail_pending_collection(&mut pending, &err);
+            return Err(err);
+        }
+        pending.attempts = 0;
+        pending.last_error = None;
+        save_pending_collection(&pending);
+    }
+
+    let extrinsics_last = match pending.aggregated_extrinsics {
+        Some(count) => count,
+        None => {
+            let count =
+                fetch_extrinsics_count(&graphql_url, &pending.from_iso, &pending.to_iso).await;
+            ensure_backfill_active(&pending)?;
+            match count {
+                Ok(count) => count,
+                Err(err) => {
+                    fail_pending_collection(&mut pending, &err);
+                    return Err(err);
+                }
+            }
+        }
+    };
+    let paged = !pending.windows.is_empty();
+    let backfill = pending.backfill;
+    let mut provenance = Provenance {
+        ts: pending.day.clone(),
+        origin: if backfill { PointOrigin::Backfill } else { PointOrigin::Refresh },
+        source: graphql_url,
+        collected_at: time(),
+        pages: pending.pages,
+        complete: true,
+    };
+    let point = DailyPoint {
+        ts: pending.day.clone(),
+        active: pending.aggregated_active.unwrap_or(seen.len() as u64),
+        new_wallets: 0,
+        publisher: None,
+    };
+    let extrinsics_point = DailyExtrinsicsPoint {
+        ts: pending.day,
+        extrinsics: extrinsics_last,
+        publisher: None,
+    };
+
+    let summary = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let (new_wallets, complete) = resolve_new_wallets(
+            &state,
+            &point.ts,
+            pending.aggregated_new_wallets,
+            backfill,
+            &seen,
+        );
+        provenance.complete = complete;
+        // A refetched past day must not replace the wallet set kept for
+        // today's diff. Without a paged wallet set the next diff would be
+        // meaningless, so forget the previous one as ingest does.
+        if !backfill {
+            state.prev_active_wallets = if paged { pending.wallets } else { Vec::new() };
+        }
+        state.pending_collection = None;
+
+        let mut point = point;
+        point.new_wallets = new_wallets;
+        let summary = format!(
+            "{}: active={}, new_wallets={}, extrinsics={}",
+            point.ts, point.active, point.new_wallets, extrinsics_point.extrinsics
+        );
+        save_revision(&mut state, &point.ts);
+        upsert_daily_point(&mut state.series, point);
+        upsert_extrinsics_point(&mut state.extrinsics_series, extrinsics_point);
+        record_provenance(&mut state, provenance);
+        build_series_payloads(&mut state);
+        state.last_updated = Some(time());
+        state.versions.daily += 1;
+        summary
+    });
+
+    update_certified_data();
+    if backfill {
+        STATE.with(|state| complete_refetch(&mut state.borrow_mut(), time()));
+    }
+
+    Ok(RefreshOutcome::Completed(summary))
+}
+
+#[ic_cdk::query]
+fn get_active_wallets_daily() -> String {
+    STATE.with(|state| state.borrow().payload.clone())
+}
+
+#[ic_cdk::query]
+fn get_extrinsics_daily() -> String {
+    STATE.with(|state| state.borrow().extrinsics_payload.clone())
+}
+
+#[ic_cdk::query]
+fn get_new_wallets_inflow() -> String {
+    STATE.with(|state| state.borrow().inflow_payload.clone())
+}
+
+#[ic_cdk::query]
+fn get_status() -> Status {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let windows_done = |pending: &PendingCollection| {
+            pending.windows.iter().filter(|window| window.complete).count() as u32
+        };
+        Status {
+            source_url: state.source_url.clone(),
+            last_updated: state.last_updated,
+            payload_bytes: state.payload.len() as u64,
+            refresh_enabled: state.refresh_enabled,
+            cycles_balance: canister_balance128(),
+            cycles_spent_today: state.cycles.spent_on(&today_label()),
+            last_refresh_cycles: state.cycles.refresh_spent,
+            daily_cycles_budget: state.cycles.daily_budget,
+            min_cycles_balance: state.cycles.min_balance,
+            pending_collection: state.pending_collection.as_ref().map(|pending| CollectionStatus {
+                day: pending.day.clone(),
+                pages: pending.pages,
+                wallets: pending.wallets.len() as u64,
+                windows_done: windows_done(pending),
+                windows_total: pending.windows.len() as u32,
+                started_at: pending.started_at,
+                attempts: pending.attempts,
+                last_error: pending.last_error.clone(),
+            }),
+            daily_version: state.versions.daily,
+            gaps: missing_days(&state),
+            refetch_queue: queued_backfill_days(&state),
+            refresh_lock: state.refresh_lock.clone(),
+            inflow_version: state.versions.inflow,
+            pull_url: state.pull.url.clone(),
+            pull_enabled: state.pull.enabled,
+            last_pulled_at: state.pull.last_pulled_at,
+            last_pull_error: state.pull.last_error.clone(),
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_cycles_report() -> CyclesReport {
+    let balance = canister_balance128();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let burn_rate_per_day = burn_rate_per_day(&state.cycles_history);
+        CyclesReport {
+            balance,
+            burn_rate_per_day,
+            estimated_days_until_freeze: days_until_freeze(balance, state.cycles.min_balance, burn_rate_per_day),
+            spent_today: state.cycles.spent_on(&today_label()),
+            daily_budget: state.cycles.daily_budget,
+            min_balance: state.cycles.min_balance,
+            history: state.cycles_history.clone(),
+        }
+    })
+}
+
+/// Returns buffered log entries at or above `level`. With `since` the oldest
+/// `limit` entries newer than that timestamp are returned (for polling);
+/// otherwise the newest `limit` entries.
+#[ic_cdk::query]
+fn get_logs(since: Option<u64>, level: Option<LogLevel>, limit: Option<u32>) -> Vec<LogEntry> {
+    assert_log_reader().unwrap_or_else(|err| ic_cdk::trap(&err));
+    let min_level = level.unwrap_or(LogLevel::Debug);
+    let limit = limit
+        .map(|limit| limit as usize)
+        .unwrap_or(DEFAULT_LOG_LIMIT)
+        .min(LOG_CAPACITY);
+    STATE.with(|state| {
+        let state = state.borrow();
+        let matching: Vec<&LogEntry> = state
+            .logs
+            .iter()
+            .filter(|entry| entry.level >= min_level)
+            .filter(|entry| since.is_none_or(|since| entry.ts > since))
+            .collect();
+        let selected = if since.is_some() {
+            &matching[..limit.min(matching.len())]
+        } else {
+            &matching[matching.len().saturating_sub(limit)..]
+        };
+        selected.iter().map(|entry| (*entry).clone()).collect()
+    })
+}
+
+#[ic_cdk::update]
+fn add_publisher(publisher: Publisher) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    let valid = match publisher.scheme {
+        SignatureScheme::Ed25519 => ed25519_key(&publisher.public_key).map(|_| ()),
+        SignatureScheme::Secp256k1 => secp256k1_key(&publisher.public_key).map(|_| ()),
+    };
+    valid.unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| {
+        let publishers = &mut state.borrow_mut().publishers;
+        publishers.retain(|existing| existing.name != publisher.name);
+        publishers.push(publisher);
+    });
+}
+
+#[ic_cdk::update]
+fn remove_publisher(name: String) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| state.borrow_mut().publishers.retain(|publisher| publisher.name != name));
+}
+
+#[ic_cdk::query]
+fn get_publishers() -> Vec<Publisher> {
+    STATE.with(|state| state.borrow().publishers.clone())
+}
+
+#[ic_cdk::update]
+fn set_require_signed_snapshots(required: bool) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| state.borrow_mut().require_signed_snapshots = required);
+}
+
+#[ic_cdk::update]
+fn add_log_reader(reader: Principal) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.log_readers.contains(&reader) {
+            state.log_readers.push(reader);
+        }
+    });
+}
+
+#[ic_cdk::update]
+fn remove_log_reader(reader: Principal) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| {
+        state.borrow_mut().log_readers.retain(|entry| *entry != reader);
+    });
+}
+
+#[ic_cdk::query]
+fn get_owner() -> Principal {
+    STATE.with(|state| state.borrow().owner)
+}
+
+#[ic_cdk::update]
+fn set_owner(new_owner: Principal) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| {
+        state.borrow_mut().owner = new_owner;
+    });
+}
+
+#[ic_cdk::update]
+fn set_source_url(url: String) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| {
+        state.borrow_mut().source_url = url;
+    });
+}
+
+#[ic_cdk::update]
+fn set_refresh_enabled(enabled: bool) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| {
+        state.borrow_mut().refresh_enabled = enabled;
+    });
 }
 
-#[derive(CandidType, Deserialize)]
-struct CanisterHttpResponse {
-    status_code: u16,
-    headers: Vec<(String, String)>,
-    body: Vec<u8>,
+#[ic_cdk::update]
+fn set_collection_strategy(metric: Metric, strategy: CollectionStrategy) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| {
+        let strategies = &mut state.borrow_mut().strategies;
+        strategies.retain(|(entry, _)| *entry != metric);
+        if !matches!(strategy, CollectionStrategy::Paging) {
+            strategies.push((metric, strategy));
+        }
+    });
+    log(
+        LogLevel::Info,
+        "config",
+        "collection strategy changed",
+        &[("metric", metric.as_str().to_string())],
+    );
 }
 
-#[derive(CandidType, Deserialize)]
-struct Status {
-    source_url: String,
-    last_updated: Option<u64>,
-    payload_bytes: u64,
-    refresh_enabled: bool,
+#[ic_cdk::query]
+fn get_collection_strategies() -> Vec<(Metric, CollectionStrategy)> {
+    STATE.with(|state| state.borrow().strategies.clone())
 }
 
-#[ic_cdk::init]
-fn init() {
-    let owner = ic_cdk::caller();
+#[ic_cdk::update]
+fn set_cycles_budget(daily_budget: u128, min_balance: u128) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
     STATE.with(|state| {
-        *state.borrow_mut() = State::new(owner);
+        let mut state = state.borrow_mut();
+        state.cycles.daily_budget = daily_budget;
+        state.cycles.min_balance = min_balance;
     });
+}
 
-    sync_certified_data();
-    init_timers();
+#[ic_cdk::update]
+fn ingest_daily_snapshot(snapshot: DailySnapshotInput, options: Option<IngestOptions>) -> String {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| state.borrow_mut().counters.ingest_snapshot_calls += 1);
+    let options = options.unwrap_or_default();
+    let key = options.idempotency_key.as_deref().map(|key| format!("snapshot:{key}"));
+    if let Some(response) = key.as_deref().and_then(remembered_response) {
+        return response;
+    }
+    check_expected_version(options.expected_version, |versions| versions.daily)
+        .unwrap_or_else(|err| ic_cdk::trap(&err));
+    let publisher = verify_snapshot(&snapshot).unwrap_or_else(|err| ic_cdk::trap(&err));
+    let snapshot_ts = snapshot.ts.clone();
+    let (payload, updated) = apply_daily_snapshot(
+        snapshot,
+        publisher.clone(),
+        PointOrigin::Ingest,
+        ic_cdk::caller().to_text(),
+    );
+    log(
+        LogLevel::Info,
+        "ingest",
+        "daily snapshot ingested",
+        &[
+            ("ts", snapshot_ts),
+            ("updated", updated.to_string()),
+            ("publisher", publisher.unwrap_or_default()),
+        ],
+    );
+    if let Some(key) = key {
+        remember_response(key, &payload);
+    }
+
+    payload
 }
 
-#[ic_cdk::post_upgrade]
-fn post_upgrade() {
-    let restored = ic_cdk::storage::stable_restore::<(State,)>().ok();
-    if let Some((state,)) = restored {
-        STATE.with(|s| *s.borrow_mut() = state);
-    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV5,)>() {
-        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
-    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV4,)>() {
-        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
-    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV3,)>() {
-        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
-    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV2,)>() {
-        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
-    } else if let Ok((legacy,)) = ic_cdk::storage::stable_restore::<(StateV1,)>() {
-        STATE.with(|s| *s.borrow_mut() = State::from(legacy));
-    } else {
-        let owner = ic_cdk::caller();
-        STATE.with(|state| *state.borrow_mut() = State::new(owner));
+#[ic_cdk::update]
+fn ingest_new_wallets_inflow(payload: String, options: Option<IngestOptions>) -> String {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| state.borrow_mut().counters.ingest_inflow_calls += 1);
+    let options = options.unwrap_or_default();
+    let key = options.idempotency_key.as_deref().map(|key| format!("inflow:{key}"));
+    if let Some(response) = key.as_deref().and_then(remembered_response) {
+        return response;
+    }
+    check_expected_version(options.expected_version, |versions| versions.inflow)
+        .unwrap_or_else(|err| ic_cdk::trap(&err));
+    let (inflow_payload, updated) = apply_inflow_payload(payload);
+    log(
+        LogLevel::Info,
+        "ingest",
+        "new wallets inflow ingested",
+        &[("bytes", inflow_payload.len().to_string()), ("updated", updated.to_string())],
+    );
+    if let Some(key) = key {
+        remember_response(key, &inflow_payload);
     }
 
-    sync_certified_data();
-    init_timers();
+    inflow_payload
 }
 
-#[ic_cdk::pre_upgrade]
-fn pre_upgrade() {
+/// Returns the stored response for a key seen within the idempotency window.
+fn remembered_response(key: &str) -> Option<String> {
+    let cutoff = time().saturating_sub(IDEMPOTENCY_WINDOW_SECS * 1_000_000_000);
     STATE.with(|state| {
-        let _ = ic_cdk::storage::stable_save((state.borrow().clone(),));
-    });
+        state
+            .borrow()
+            .idempotency_keys
+            .iter()
+            .find(|record| record.key == key && record.at >= cutoff)
+            .map(|record| record.response.clone())
+    })
 }
 
-fn init_timers() {
-    set_timer_interval(Duration::from_secs(24 * 60 * 60), || {
-        spawn(async {
-            let _ = refresh_internal().await;
+fn remember_response(key: String, response: &str) {
+    let now = time();
+    let cutoff = now.saturating_sub(IDEMPOTENCY_WINDOW_SECS * 1_000_000_000);
+    STATE.with(|state| {
+        let records = &mut state.borrow_mut().idempotency_keys;
+        records.retain(|record| record.at >= cutoff && record.key != key);
+        if records.len() >= MAX_IDEMPOTENCY_KEYS {
+            records.remove(0);
+        }
+        records.push(IdempotencyRecord {
+            key,
+            at: now,
+            response: response.to_string(),
         });
     });
 }
 
-fn assert_owner() -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    let owner = STATE.with(|state| state.borrow().owner);
-    if caller == owner {
-        Ok(())
-    } else {
-        Err("unauthorized".to_string())
+fn check_expected_version(
+    expected: Option<u64>,
+    current: impl Fn(&SeriesVersions) -> u64,
+) -> Result<(), String> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+    let current = STATE.with(|state| current(&state.borrow().versions));
+    if expected != current {
+        return Err(format!("conflict: expected version {expected}, current version {current}"));
     }
+    Ok(())
 }
 
-async fn refresh_internal() -> Result<String, String> {
-    let (graphql_url, refresh_enabled, payload) = STATE.with(|state| {
-        let state = state.borrow();
-        (
-            state.source_url.clone(),
-            state.refresh_enabled,
-            state.payload.clone(),
-        )
+#[ic_cdk::update]
+fn set_pull_config(config: PullConfigInput) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    if config.interval_secs < MIN_PULL_INTERVAL_SECS {
+        ic_cdk::trap(&format!("interval_secs must be at least {MIN_PULL_INTERVAL_SECS}"));
+    }
+    STATE.with(|state| {
+        let pull = &mut state.borrow_mut().pull;
+        pull.url = config.url;
+        pull.enabled = config.enabled;
+        pull.interval_secs = config.interval_secs;
     });
-    if !refresh_enabled {
-        return Ok(payload);
-    }
-    let now = current_time()?;
-    let last_start = now - TimeDuration::hours(24);
-    let to_iso = now
-        .format(&Rfc3339)
-        .map_err(|_| "failed to format to timestamp".to_string())?;
-    let last_iso = last_start
-        .format(&Rfc3339)
-        .map_err(|_| "failed to format last timestamp".to_string())?;
-
-    let active_last = fetch_active_wallets_set(&graphql_url, &last_iso, &to_iso).await?;
-    let extrinsics_last = fetch_extrinsics_count(&graphql_url, &last_iso, &to_iso).await?;
-    let day_label = now.date().to_string();
+    schedule_pull_timer();
+}
+
+#[ic_cdk::update]
+fn set_cors_config(config: CorsConfig) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    for origin in &config.allowed_origins {
+        let valid = origin == "*"
+            || ((origin.starts_with("https://") || origin.starts_with("http://"))
+                && !origin.ends_with('/'));
+        if !valid {
+            ic_cdk::trap(&format!("invalid origin {origin}: expected \"*\" or scheme://host"));
+        }
+    }
+    STATE.with(|state| state.borrow_mut().cors = config);
+}
+
+#[ic_cdk::query]
+fn get_cors_config() -> CorsConfig {
+    STATE.with(|state| state.borrow().cors.clone())
+}
+
+#[ic_cdk::update]
+async fn pull_now() -> String {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    pull_snapshot().await.unwrap_or_else(|err| ic_cdk::trap(&err))
+}
+
+/// Upserts a daily snapshot and rebuilds/recertifies payloads. Returns the
+/// active-wallets payload and whether anything changed.
+fn apply_daily_snapshot(
+    snapshot: DailySnapshotInput,
+    publisher: Option<String>,
+    origin: PointOrigin,
+    source: String,
+) -> (String, bool) {
+    let provenance = Provenance {
+        ts: snapshot.ts.clone(),
+        origin,
+        source,
+        collected_at: time(),
+        pages: 0,
+        complete: true,
+    };
     let point = DailyPoint {
-        ts: day_label.clone(),
-        active: active_last.len() as u64,
-        new_wallets: 0,
+        ts: snapshot.ts.clone(),
+        active: snapshot.active,
+        new_wallets: snapshot.new_wallets,
+        publisher: publisher.clone(),
     };
     let extrinsics_point = DailyExtrinsicsPoint {
-        ts: day_label,
-        extrinsics: extrinsics_last,
+        ts: snapshot.ts,
+        extrinsics: snapshot.extrinsics,
+        publisher,
     };
 
-    let (payload, extrinsics_payload, inflow_payload) = STATE.with(|state| {
+    let (payload, updated) = STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let new_wallets = if state.prev_active_wallets.is_empty() {
-            0
-        } else {
-            let prev_set: HashSet<String> = state.prev_active_wallets.iter().cloned().collect();
-            active_last.difference(&prev_set).count() as u64
-        };
-        state.prev_active_wallets = active_last.iter().cloned().collect();
-
-        let mut point = point;
-        point.new_wallets = new_wallets;
+        let same_point = state
+            .series
+            .iter()
+            .any(|entry| {
+                entry.ts == point.ts
+                    && entry.active == point.active
+                    && entry.new_wallets == point.new_wallets
+                    && entry.publisher == point.publisher
+            });
+        let same_ext = state.extrinsics_series.iter().any(|entry| {
+            entry.ts == extrinsics_point.ts
+                && entry.extrinsics == extrinsics_point.extrinsics
+                && entry.publisher == extrinsics_point.publisher
+        });
+        if same_point && same_ext {
+            return (state.payload.clone(), false);
+        }
+        save_revision(&mut state, &point.ts);
         upsert_daily_point(&mut state.series, point);
         upsert_extrinsics_point(&mut state.extrinsics_series, extrinsics_point);
-        state.payload = build_payload(&state.series);
-        state.extrinsics_payload = build_extrinsics_payload(&state.extrinsics_series);
+        record_provenance(&mut state, provenance);
+        build_series_payloads(&mut state);
         state.last_updated = Some(time());
-        (
-            state.payload.clone(),
-            state.extrinsics_payload.clone(),
-            state.inflow_payload.clone(),
-        )
+        state.prev_active_wallets.clear();
+        state.versions.daily += 1;
+        (state.payload.clone(), true)
+    });
+
+    if updated {
+        update_certified_data();
+    }
+    (payload, updated)
+}
+
+fn apply_inflow_payload(payload: String) -> (String, bool) {
+    let (inflow_payload, updated) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.inflow_payload == payload {
+            return (state.inflow_payload.clone(), false);
+        }
+        state.inflow_payload = payload;
+        state.last_updated = Some(time());
+        state.versions.inflow += 1;
+        (state.inflow_payload.clone(), true)
+    });
+
+    if updated {
+        update_certified_data();
+    }
+    (inflow_payload, updated)
+}
+
+/// Fetches the configured snapshot envelope
+/// `{ "snapshot": { ts, active, new_wallets, extrinsics }, "inflow": "..." }`,
+/// validates it and upserts it like a push would. `inflow` is optional and
+/// holds the payload text exactly as `ingest_new_wallets_inflow` takes it.
+async fn pull_snapshot() -> Result<String, String> {
+    let url = STATE.with(|state| state.borrow().pull.url.clone());
+    if url.is_empty() {
+        return Err("pull url not configured".to_string());
+    }
+    let result = fetch_and_apply_snapshot(&url).await;
+    STATE.with(|state| {
+        let pull = &mut state.borrow_mut().pull;
+        pull.last_pulled_at = Some(time());
+        match &result {
+            Ok(ts) => {
+                pull.last_snapshot_ts = Some(ts.clone());
+                pull.last_error = None;
+            }
+            Err(err) => pull.last_error = Some(err.clone()),
+        }
     });
+    match &result {
+        Ok(ts) => log(LogLevel::Info, "pull", "snapshot pulled", &[("ts", ts.clone())]),
+        Err(err) => log(
+            LogLevel::Error,
+            "pull",
+            "snapshot pull failed",
+            &[("error", err.clone())],
+        ),
+    }
+    result
+}
 
-    update_certified_data(&payload, &extrinsics_payload, &inflow_payload);
+async fn fetch_and_apply_snapshot(url: &str) -> Result<String, String> {
+    let envelope =
+        json_get_request(url, "inflow,inflow_signature,signature,snapshot", PULL_MAX_RESPONSE_BYTES)
+            .await?;
+    let mut snapshot = parse_snapshot(envelope.get("snapshot").unwrap_or(&Value::Null))?;
+    snapshot.signature = parse_signature(envelope.get("signature").unwrap_or(&Value::Null))?;
+    validate_snapshot(&snapshot)?;
+    let publisher = verify_snapshot(&snapshot)?;
+    let inflow = match envelope.get("inflow") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(inflow)) => {
+            serde_json::from_str::<serde_json::Map<String, Value>>(inflow)
+                .map_err(|err| format!("inflow is not a JSON object: {err}"))?;
+            let signature = envelope.get("inflow_signature").unwrap_or(&Value::Null);
+            verify_signed("inflow", parse_signature(signature)?.as_ref(), inflow.as_bytes())?;
+            Some(inflow.clone())
+        }
+        Some(_) => return Err("inflow must be a JSON string".to_string()),
+    };
 
-    Ok(payload)
+    let ts = snapshot.ts.clone();
+    apply_daily_snapshot(snapshot, publisher, PointOrigin::Pull, url.to_string());
+    if let Some(inflow) = inflow {
+        apply_inflow_payload(inflow);
+    }
+    Ok(ts)
 }
 
-#[ic_cdk::query]
-fn get_active_wallets_daily() -> String {
-    STATE.with(|state| state.borrow().payload.clone())
+fn parse_snapshot(value: &Value) -> Result<DailySnapshotInput, String> {
+    let field = |name: &str| {
+        value
+            .get(name)
+            .and_then(|field| field.as_u64())
+            .ok_or_else(|| format!("snapshot.{name} missing or not an unsigned integer"))
+    };
+    Ok(DailySnapshotInput {
+        ts: value
+            .get("ts")
+            .and_then(|ts| ts.as_str())
+            .ok_or_else(|| "snapshot.ts missing".to_string())?
+            .to_string(),
+        active: field("active")?,
+        new_wallets: field("new_wallets")?,
+        extrinsics: field("extrinsics")?,
+        signature: None,
+    })
+}
+
+/// Reads an optional envelope signature `{ publisher, signature }` where
+/// `signature` is base64.
+fn parse_signature(value: &Value) -> Result<Option<SnapshotSignature>, String> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    let publisher = value
+        .get("publisher")
+        .and_then(|publisher| publisher.as_str())
+        .ok_or_else(|| "signature.publisher missing".to_string())?;
+    let signature = value
+        .get("signature")
+        .and_then(|signature| signature.as_str())
+        .ok_or_else(|| "signature.signature missing".to_string())?;
+    let signature = general_purpose::STANDARD
+        .decode(signature)
+        .map_err(|err| format!("signature is not valid base64: {err}"))?;
+    Ok(Some(SnapshotSignature {
+        publisher: publisher.to_string(),
+        signature,
+    }))
+}
+
+fn snapshot_message(snapshot: &DailySnapshotInput) -> Vec<u8> {
+    json!({
+        "ts": snapshot.ts,
+        "active": snapshot.active,
+        "new_wallets": snapshot.new_wallets,
+        "extrinsics": snapshot.extrinsics,
+    })
+    .to_string()
+    .into_bytes()
+}
+
+/// Checks the snapshot signature against the registered publishers and
+/// returns the verified publisher name. Unsigned snapshots pass only while
+/// `require_signed_snapshots` is off.
+fn verify_snapshot(snapshot: &DailySnapshotInput) -> Result<Option<String>, String> {
+    verify_signed("snapshot", snapshot.signature.as_ref(), &snapshot_message(snapshot))
+}
+
+/// Verifies `signature` over `message`; `subject` names what was signed in
+/// errors. Pulled inflow is signed over its exact payload text.
+fn verify_signed(
+    subject: &str,
+    signature: Option<&SnapshotSignature>,
+    message: &[u8],
+) -> Result<Option<String>, String> {
+    let Some(signature) = signature else {
+        let required = STATE.with(|state| state.borrow().require_signed_snapshots);
+        if required {
+            return Err(format!("{subject} signature required"));
+        }
+        return Ok(None);
+    };
+    let publisher = STATE
+        .with(|state| {
+            state
+                .borrow()
+                .publishers
+                .iter()
+                .find(|publisher| publisher.name == signature.publisher)
+                .cloned()
+        })
+        .ok_or_else(|| format!("unknown publisher {}", signature.publisher))?;
+    verify_signature(&publisher, message, &signature.signature)?;
+    Ok(Some(publisher.name))
+}
+
+fn verify_signature(
+    publisher: &Publisher,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), String> {
+    let invalid = |err: String| format!("invalid signature from {}: {err}", publisher.name);
+    match publisher.scheme {
+        SignatureScheme::Ed25519 => {
+            let key = ed25519_key(&publisher.public_key)?;
+            let signature = ed25519_dalek::Signature::from_slice(signature)
+                .map_err(|err| invalid(err.to_string()))?;
+            key.verify_strict(message, &signature)
+                .map_err(|err| invalid(err.to_string()))
+        }
+        SignatureScheme::Secp256k1 => {
+            let key = secp256k1_key(&publisher.public_key)?;
+            let signature = k256::ecdsa::Signature::from_slice(signature)
+                .map_err(|err| invalid(err.to_string()))?;
+            key.verify(message, &signature)
+                .map_err(|err| invalid(err.to_string()))
+        }
+    }
+}
+
+fn ed25519_key(public_key: &[u8]) -> Result<ed25519_dalek::VerifyingKey, String> {
+    let bytes: &[u8; 32] = public_key
+        .try_into()
+        .map_err(|_| "ed25519 public key must be 32 bytes".to_string())?;
+    ed25519_dalek::VerifyingKey::from_bytes(bytes)
+        .map_err(|err| format!("invalid ed25519 public key: {err}"))
+}
+
+fn secp256k1_key(public_key: &[u8]) -> Result<k256::ecdsa::VerifyingKey, String> {
+    k256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+        .map_err(|err| format!("invalid secp256k1 public key: {err}"))
 }
 
+fn parse_day(day: &str) -> Result<Date, String> {
+    Date::parse(day, format_description!("[year]-[month]-[day]"))
+        .map_err(|_| format!("invalid day {day}, expected YYYY-MM-DD"))
+}
+
+/// Rejects snapshots whose day is malformed, in the future or outside the
+/// retention window, and counts that cannot be consistent.
+fn validate_snapshot(snapshot: &DailySnapshotInput) -> Result<(), String> {
+    let day = parse_day(&snapshot.ts)?;
+    let today = current_time()?.date();
+    if day > today {
+        return Err(format!("snapshot day {day} is in the future"));
+    }
+    if day < today - TimeDuration::days(DEFAULT_DAYS as i64) {
+        return Err(format!("snapshot day {day} is outside the retention window"));
+    }
+    if snapshot.new_wallets > snapshot.active {
+        return Err("snapshot new_wallets exceeds active".to_string());
+    }
+    Ok(())
+}
+
+/// Prior values of a metric for a day, oldest first.
 #[ic_cdk::query]
-fn get_extrinsics_daily() -> String {
-    STATE.with(|state| state.borrow().extrinsics_payload.clone())
+fn get_point_history(metric: Metric, ts: String) -> Vec<PointHistoryEntry> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .revisions
+            .iter()
+            .filter(|revision| revision.ts == ts)
+            .map(|revision| {
+                let (value, publisher) = match metric {
+                    Metric::ActiveWallets => revision
+                        .point
+                        .as_ref()
+                        .map(|point| (Some(point.active), point.publisher.clone()))
+                        .unwrap_or_default(),
+                    Metric::NewWallets => revision
+                        .point
+                        .as_ref()
+                        .map(|point| (Some(point.new_wallets), point.publisher.clone()))
+                        .unwrap_or_default(),
+                    Metric::Extrinsics => revision
+                        .extrinsics
+                        .as_ref()
+                        .map(|point| (Some(point.extrinsics), point.publisher.clone()))
+                        .unwrap_or_default(),
+                };
+                PointHistoryEntry {
+                    replaced_at: revision.replaced_at,
+                    value,
+                    publisher,
+                    provenance: revision.provenance.clone(),
+                }
+            })
+            .collect()
+    })
+}
+
+/// Restores the day's most recent prior revision and drops it from history.
+#[ic_cdk::update]
+fn revert_point(ts: String) -> String {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    let payload = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let index = state
+            .revisions
+            .iter()
+            .rposition(|revision| revision.ts == ts)
+            .ok_or_else(|| format!("no revisions for {ts}"))?;
+        let revision = state.revisions.remove(index);
+        let removed: Vec<String> = restore_revision(&mut state, revision).into_iter().collect();
+        rebuild_daily_payloads(&mut state, &removed);
+        Ok::<_, String>(state.payload.clone())
+    })
+    .unwrap_or_else(|err| ic_cdk::trap(&err));
+    update_certified_data();
+    audit("revert_point", ts.clone());
+    log(LogLevel::Warn, "admin", "point reverted", &[("ts", ts)]);
+
+    payload
+}
+
+/// Restores every day to the values it had at `timestamp` (nanoseconds) and
+/// drops the newer revisions.
+#[ic_cdk::update]
+fn revert_to(timestamp: u64) -> String {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    let (payload, reverted) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let (newer, older): (Vec<_>, Vec<_>) = std::mem::take(&mut state.revisions)
+            .into_iter()
+            .partition(|revision| revision.replaced_at > timestamp);
+        state.revisions = older;
+        let mut restored: Vec<String> = Vec::new();
+        let mut removed: Vec<String> = Vec::new();
+        // The earliest revision after `timestamp` holds the value current at it.
+        for revision in newer {
+            if !restored.contains(&revision.ts) {
+                restored.push(revision.ts.clone());
+                removed.extend(restore_revision(&mut state, revision));
+            }
+        }
+        if !restored.is_empty() {
+            rebuild_daily_payloads(&mut state, &removed);
+        }
+        (state.payload.clone(), restored.len())
+    });
+    if reverted > 0 {
+        update_certified_data();
+    }
+    audit("revert_to", format!("timestamp={timestamp} days={reverted}"));
+    log(
+        LogLevel::Warn,
+        "admin",
+        "series reverted",
+        &[("timestamp", timestamp.to_string()), ("days", reverted.to_string())],
+    );
+
+    payload
 }
 
+/// Days in the retention window missing a daily point.
 #[ic_cdk::query]
-fn get_new_wallets_inflow() -> String {
-    STATE.with(|state| state.borrow().inflow_payload.clone())
+fn get_gaps() -> Vec<String> {
+    STATE.with(|state| missing_days(&state.borrow()))
+}
+
+/// Toggles explicit `null` points for missing days in the daily JSON.
+#[ic_cdk::update]
+fn set_emit_gap_nulls(enabled: bool) {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.emit_gap_nulls = enabled;
+        build_series_payloads(&mut state);
+    });
+    update_certified_data();
+}
+
+/// Queues every current gap for a refetch of its calendar day and returns the
+/// number of newly queued days.
+#[ic_cdk::update]
+fn backfill_gaps() -> u32 {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    let gaps = STATE.with(|state| missing_days(&state.borrow()));
+    let queued = queue_refetch(gaps);
+    log(LogLevel::Info, "backfill", "gaps queued", &[("days", queued.to_string())]);
+
+    queued
+}
+
+/// Recollects every UTC day in `from_date..=to_date` (YYYY-MM-DD) from the
+/// configured source, one day per collection, within the cycles budget.
+/// Days must lie in the retention window since older points are not kept.
+#[ic_cdk::update]
+fn backfill(from_date: String, to_date: String) -> u32 {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    let days = backfill_days(&from_date, &to_date).unwrap_or_else(|err| ic_cdk::trap(&err));
+    let queued = queue_refetch(days);
+    log(
+        LogLevel::Info,
+        "backfill",
+        "backfill queued",
+        &[("from", from_date), ("to", to_date), ("days", queued.to_string())],
+    );
+
+    queued
 }
 
 #[ic_cdk::query]
-fn get_status() -> Status {
+fn get_backfill_status() -> BackfillStatus {
     STATE.with(|state| {
         let state = state.borrow();
-        Status {
-            source_url: state.source_url.clone(),
-            last_updated: state.last_updated,
-            payload_bytes: state.payload.len() as u64,
-            refresh_enabled: state.refresh_enabled,
+        BackfillStatus {
+            progress: state.backfill_progress.clone(),
+            current_day: state
+                .pending_collection
+                .as_ref()
+                .filter(|pending| pending.backfill)
+                .map(|pending| pending.day.clone()),
+            queued: queued_backfill_days(&state),
+            failed: failed_backfill_days(&state),
+        }
+    })
+}
+
+/// Drops queued days and any in-flight refetch. Days already collected stay.
+#[ic_cdk::update]
+fn cancel_backfill() {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    let now = time();
+    let dropped = STATE.with(|state| cancel_backfill_jobs(&mut state.borrow_mut(), now));
+    log(LogLevel::Warn, "backfill", "backfill cancelled", &[("dropped", dropped.to_string())]);
+}
+
+/// Cancels queued and running backfill jobs, frees the collection slot and
+/// closes the run. Returns the number of jobs cancelled.
+fn cancel_backfill_jobs(state: &mut State, now: u64) -> usize {
+    let mut dropped = 0;
+    for job in state.jobs.iter_mut() {
+        let open = matches!(job.state, JobState::Queued | JobState::Running);
+        if open && matches!(job.kind, JobKind::Backfill { .. }) {
+            job.state = JobState::Cancelled;
+            job.updated_at = now;
+            dropped += 1;
+        }
+    }
+    if state.pending_collection.as_ref().is_some_and(|pending| pending.backfill) {
+        state.pending_collection = None;
+    }
+    if let Some(progress) = state.backfill_progress.as_mut() {
+        if progress.finished_at.is_none() {
+            progress.cancelled = true;
+            progress.finished_at = Some(now);
+        }
+    }
+    dropped
+}
+
+fn backfill_days(from_date: &str, to_date: &str) -> Result<Vec<String>, String> {
+    let from = parse_day(from_date)?;
+    let to = parse_day(to_date)?;
+    let today = current_time()?.date();
+    if from > to {
+        return Err("from_date is after to_date".to_string());
+    }
+    if to >= today {
+        return Err("backfill only covers days before today".to_string());
+    }
+    if from < today - TimeDuration::days(DEFAULT_DAYS as i64 - 1) {
+        return Err(format!("from_date is older than the {DEFAULT_DAYS}-day retention window"));
+    }
+    let mut days = Vec::new();
+    let mut day = from;
+    while day <= to {
+        days.push(day.to_string());
+        day += TimeDuration::days(1);
+    }
+    Ok(days)
+}
+
+fn queued_backfill_days(state: &State) -> Vec<String> {
+    state
+        .jobs
+        .iter()
+        .filter(|job| job.state == JobState::Queued)
+        .filter_map(|job| match &job.kind {
+            JobKind::Backfill { day } => Some(day.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn failed_backfill_days(state: &State) -> Vec<String> {
+    let Some(progress) = &state.backfill_progress else {
+        return Vec::new();
+    };
+    state
+        .jobs
+        .iter()
+        .filter(|job| matches!(job.state, JobState::Failed | JobState::Cancelled))
+        .filter(|job| job.created_at >= progress.started_at)
+        .filter_map(|job| match &job.kind {
+            JobKind::Backfill { day } => Some(day.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Queues a backfill job per day not already queued and accounts for them in
+/// the current backfill run, starting a new run if none is active.
+fn queue_refetch(days: Vec<String>) -> u32 {
+    let days = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let queued = queued_backfill_days(&state);
+        let days: Vec<String> = days.into_iter().filter(|day| !queued.contains(day)).collect();
+        let (Some(first), Some(last)) = (days.first().cloned(), days.last().cloned()) else {
+            return days;
+        };
+        match state.backfill_progress.as_mut() {
+            Some(progress) if progress.finished_at.is_none() => {
+                progress.total += days.len() as u32;
+                progress.from_date = progress.from_date.clone().min(first);
+                progress.to_date = progress.to_date.clone().max(last);
+            }
+            _ => {
+                state.backfill_progress = Some(BackfillProgress {
+                    from_date: first,
+                    to_date: last,
+                    total: days.len() as u32,
+                    completed: 0,
+                    started_at: time(),
+                    finished_at: None,
+                    cancelled: false,
+                });
+            }
         }
-    })
+        days
+    });
+    for day in &days {
+        enqueue_job(JobKind::Backfill { day: day.clone() }, 0);
+    }
+    days.len() as u32
 }
 
-#[ic_cdk::query]
-fn get_owner() -> Principal {
-    STATE.with(|state| state.borrow().owner)
+/// Counts a backfill day, collected or given up on, towards the active run
+/// and closes the run once no backfill days remain queued.
+fn complete_refetch(state: &mut State, now: u64) {
+    let queue_empty = queued_backfill_days(state).is_empty();
+    if let Some(progress) = state.backfill_progress.as_mut() {
+        if progress.finished_at.is_none() {
+            progress.completed += 1;
+            if queue_empty {
+                progress.finished_at = Some(now);
+            }
+        }
+    }
 }
 
+/// Deletes a metric's points for days in `from..=to` (YYYY-MM-DD). Active and
+/// new wallets share a point, so deleting either removes both for the day.
+/// Deleted values stay in the revision history and can be reverted.
 #[ic_cdk::update]
-fn set_owner(new_owner: Principal) {
+fn delete_points(metric: Metric, from: String, to: String) -> u32 {
     assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
-    STATE.with(|state| {
-        state.borrow_mut().owner = new_owner;
+    parse_day(&from).and(parse_day(&to)).unwrap_or_else(|err| ic_cdk::trap(&err));
+    let in_range = |ts: &str| ts >= from.as_str() && ts <= to.as_str();
+    let deleted = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let days: Vec<String> = match metric {
+            Metric::ActiveWallets | Metric::NewWallets => {
+                state.series.iter().map(|point| point.ts.clone()).collect()
+            }
+            Metric::Extrinsics => {
+                state.extrinsics_series.iter().map(|point| point.ts.clone()).collect()
+            }
+        };
+        let days: Vec<String> = days.into_iter().filter(|ts| in_range(ts)).collect();
+        for ts in &days {
+            save_revision(&mut state, ts);
+        }
+        match metric {
+            Metric::ActiveWallets | Metric::NewWallets => {
+                state.series.retain(|point| !in_range(&point.ts));
+            }
+            Metric::Extrinsics => state.extrinsics_series.retain(|point| !in_range(&point.ts)),
+        }
+        if !days.is_empty() {
+            let removed: &[String] = if metric == Metric::Extrinsics { &[] } else { &days };
+            rebuild_daily_payloads(&mut state, removed);
+        }
+        days.len() as u32
     });
+    if deleted > 0 {
+        update_certified_data();
+    }
+    audit(
+        "delete_points",
+        format!("metric={} from={from} to={to} deleted={deleted}", metric.as_str()),
+    );
+    log(
+        LogLevel::Warn,
+        "admin",
+        "points deleted",
+        &[("metric", metric.as_str().to_string()), ("deleted", deleted.to_string())],
+    );
+
+    deleted
 }
 
+/// Removes every point of a metric's series (see `delete_points`).
+/// Like deletions, the cleared values remain revertible.
 #[ic_cdk::update]
-fn set_source_url(url: String) {
+fn clear_series(metric: Metric) -> u32 {
     assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
-    STATE.with(|state| {
-        state.borrow_mut().source_url = url;
+    let cleared = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let days: Vec<String> = match metric {
+            Metric::ActiveWallets | Metric::NewWallets => {
+                state.series.iter().map(|point| point.ts.clone()).collect()
+            }
+            Metric::Extrinsics => {
+                state.extrinsics_series.iter().map(|point| point.ts.clone()).collect()
+            }
+        };
+        for ts in &days {
+            save_revision(&mut state, ts);
+        }
+        match metric {
+            Metric::ActiveWallets | Metric::NewWallets => state.series.clear(),
+            Metric::Extrinsics => state.extrinsics_series.clear(),
+        }
+        let removed: &[String] = if metric == Metric::Extrinsics { &[] } else { &days };
+        rebuild_daily_payloads(&mut state, removed);
+        days.len() as u32
     });
+    update_certified_data();
+    audit("clear_series", format!("metric={} cleared={cleared}", metric.as_str()));
+    log(
+        LogLevel::Warn,
+        "admin",
+        "series cleared",
+        &[("metric", metric.as_str().to_string()), ("cleared", cleared.to_string())],
+    );
+
+    cleared
 }
 
 #[ic_cdk::update]
-fn set_refresh_enabled(enabled: bool) {
+fn reset_inflow() {
     assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
     STATE.with(|state| {
-        state.borrow_mut().refresh_enabled = enabled;
+        let mut state = state.borrow_mut();
+        state.inflow_payload = default_inflow_payload();
+        state.last_updated = Some(time());
+        state.versions.inflow += 1;
     });
+    update_certified_data();
+    audit("reset_inflow", String::new());
+    log(LogLevel::Warn, "admin", "inflow reset", &[]);
 }
 
-#[ic_cdk::update]
-fn ingest_daily_snapshot(snapshot: DailySnapshotInput) -> String {
+#[ic_cdk::query]
+fn get_audit_log() -> Vec<AuditEntry> {
     assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
-    let point = DailyPoint {
-        ts: snapshot.ts.clone(),
-        active: snapshot.active,
-        new_wallets: snapshot.new_wallets,
-    };
-    let extrinsics_point = DailyExtrinsicsPoint {
-        ts: snapshot.ts,
-        extrinsics: snapshot.extrinsics,
-    };
+    STATE.with(|state| state.borrow().audit_log.clone())
+}
 
-    let (payload, extrinsics_payload, inflow_payload, updated) = STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        let same_point = state
-            .series
-            .iter()
-            .any(|entry| entry.ts == point.ts && entry.active == point.active && entry.new_wallets == point.new_wallets);
-        let same_ext = state
-            .extrinsics_series
-            .iter()
-            .any(|entry| entry.ts == extrinsics_point.ts && entry.extrinsics == extrinsics_point.extrinsics);
-        if same_point && same_ext {
-            return (
-                state.payload.clone(),
-                state.extrinsics_payload.clone(),
-                state.inflow_payload.clone(),
-                false,
-            );
+fn audit(action: &str, details: String) {
+    let entry = AuditEntry {
+        at: time(),
+        caller: ic_cdk::caller(),
+        action: action.to_string(),
+        details,
+    };
+    STATE.with(|state| {
+        let audit_log = &mut state.borrow_mut().audit_log;
+        if audit_log.len() >= AUDIT_CAPACITY {
+            audit_log.remove(0);
         }
-        upsert_daily_point(&mut state.series, point);
-        upsert_extrinsics_point(&mut state.extrinsics_series, extrinsics_point);
-        state.payload = build_payload(&state.series);
-        state.extrinsics_payload = build_extrinsics_payload(&state.extrinsics_series);
-        state.last_updated = Some(time());
-        state.prev_active_wallets.clear();
-        (
-            state.payload.clone(),
-            state.extrinsics_payload.clone(),
-            state.inflow_payload.clone(),
-            true,
-        )
+        audit_log.push(entry);
     });
+}
 
-    if updated {
-        update_certified_data(&payload, &extrinsics_payload, &inflow_payload);
-    }
+/// Queues a refresh (or joins the one already queued) and returns its job id;
+/// poll `get_job` for progress.
+#[ic_cdk::update]
+fn refresh_now() -> u64 {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    enqueue_job(JobKind::Refresh, 0)
+}
 
-    payload
+#[ic_cdk::query]
+fn get_job(id: u64) -> Option<Job> {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| state.borrow().jobs.iter().find(|job| job.id == id).cloned())
+}
+
+#[ic_cdk::query]
+fn list_jobs(state_filter: Option<JobState>) -> Vec<Job> {
+    assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
+    STATE.with(|state| {
+        state
+            .borrow()
+            .jobs
+            .iter()
+            .filter(|job| state_filter.is_none_or(|filter| job.state == filter))
+            .cloned()
+            .collect()
+    })
 }
 
+/// Cancels a queued job. Running jobs cannot be interrupted.
 #[ic_cdk::update]
-fn ingest_new_wallets_inflow(payload: String) -> String {
+fn cancel_job(id: u64) {
     assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
-    let (active_payload, extrinsics_payload, inflow_payload, updated) = STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        if state.inflow_payload == payload {
-            return (
-                state.payload.clone(),
-                state.extrinsics_payload.clone(),
-                state.inflow_payload.clone(),
-                false,
-            );
+    let kind = update_job(id, |job| match job.state {
+        JobState::Queued => {
+            job.state = JobState::Cancelled;
+            Ok(())
         }
-        state.inflow_payload = payload;
-        state.last_updated = Some(time());
-        (
-            state.payload.clone(),
-            state.extrinsics_payload.clone(),
-            state.inflow_payload.clone(),
-            true,
-        )
-    });
-
-    if updated {
-        update_certified_data(&active_payload, &extrinsics_payload, &inflow_payload);
+        JobState::Running => Err(format!("job {id} is running")),
+        _ => Err(format!("job {id} already finished")),
+    })
+    .unwrap_or_else(|err| ic_cdk::trap(&err));
+    if let JobKind::Backfill { day } = kind {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            release_backfill_collection(&mut state, &day);
+            complete_refetch(&mut state, time());
+        });
     }
-
-    inflow_payload
+    schedule_jobs();
 }
 
+/// Requeues a failed or cancelled job with a fresh attempt count.
 #[ic_cdk::update]
-async fn refresh_now() -> String {
+fn retry_job(id: u64) {
     assert_owner().unwrap_or_else(|err| ic_cdk::trap(&err));
-    refresh_internal().await.unwrap_or_else(|err| ic_cdk::trap(&err))
+    update_job(id, |job| match job.state {
+        JobState::Failed | JobState::Cancelled => {
+            job.state = JobState::Queued;
+            job.attempts = 0;
+            job.next_run_at = time();
+            Ok(())
+        }
+        _ => Err(format!("job {id} is not failed or cancelled")),
+    })
+    .unwrap_or_else(|err| ic_cdk::trap(&err));
+    schedule_jobs();
+}
+
+fn update_job(
+    id: u64,
+    apply: impl FnOnce(&mut Job) -> Result<(), String>,
+) -> Result<JobKind, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let job = state
+            .jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or_else(|| format!("unknown job {id}"))?;
+        apply(job)?;
+        job.updated_at = time();
+        Ok(job.kind.clone())
+    })
 }
 
 #[ic_cdk::query]
 fn http_request(req: CanisterHttpRequest) -> CanisterHttpResponse {
+    let cors = STATE.with(|state| state.borrow().cors.clone());
+    let origin = request_header(&req, "origin");
+    let preflight = req.method.eq_ignore_ascii_case("OPTIONS");
+    let head = req.method.eq_ignore_ascii_case("HEAD");
+    let mut response = if preflight {
+        CanisterHttpResponse {
+            status_code: 204,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    } else {
+        serve_request(&req)
+    };
+    if let Some(allow_origin) = cors.allow_origin(origin) {
+        let headers = &mut response.headers;
+        headers.push(("Access-Control-Allow-Origin".to_string(), allow_origin));
+        if preflight {
+            let methods = cors.allowed_methods.join(", ");
+            headers.push(("Access-Control-Allow-Methods".to_string(), methods));
+            let allowed_headers = cors.allowed_headers.join(", ");
+            headers.push(("Access-Control-Allow-Headers".to_string(), allowed_headers));
+            let max_age = cors.max_age_secs.to_string();
+            headers.push(("Access-Control-Max-Age".to_string(), max_age));
+        }
+    }
+    if !cors.allows_any_origin() {
+        add_vary(&mut response.headers, "Origin");
+    }
+    // HEAD gets exactly the GET headers. The body is dropped only on the raw
+    // domain: v1 gateways verify the body against the path's entry, and the
+    // gateway itself strips it from HEAD responses.
+    if head && is_raw_domain(&req) {
+        response.body.clear();
+    }
+    response
+}
+
+fn serve_request(req: &CanisterHttpRequest) -> CanisterHttpResponse {
     let path = normalize_path(&req.url);
-    if !CERTIFIED_PATHS.contains(&path.as_str()) {
+    let Some(route) = ROUTES.iter().find(|route| route.path == path) else {
         return CanisterHttpResponse {
             status_code: 404,
             headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
             body: b"Not found".to_vec(),
         };
+    };
+    let readable = ["GET", "HEAD"].iter().any(|method| req.method.eq_ignore_ascii_case(method));
+    if !readable {
+        return method_not_allowed();
+    }
+    if path == METRICS_PATH {
+        return CanisterHttpResponse {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), "text/plain; version=0.0.4".to_string()),
+                ("Cache-Control".to_string(), "no-store".to_string()),
+            ],
+            body: render_metrics().into_bytes(),
+        };
     }
-
-    let payload = STATE.with(|state| {
-        let state = state.borrow();
-        match path.as_str() {
-            "/extrinsics-daily.json" => state.extrinsics_payload.clone(),
-            "/new-wallets-inflow.json" => state.inflow_payload.clone(),
-            _ => state.payload.clone(),
+    if wants_meta(&req.url) {
+        let meta_payload = STATE.with(|state| {
+            let state = state.borrow();
+            match path.as_str() {
+                "/active-wallets-daily.json" => {
+                    Some(build_meta_payload(&state.series, &state.provenance))
+                }
+                "/extrinsics-daily.json" => {
+                    Some(build_meta_payload(&state.extrinsics_series, &state.provenance))
+                }
+                _ => None,
+            }
+        });
+        // Built per request, so it cannot be certified; fetch it via the raw domain.
+        if let Some(meta_payload) = meta_payload {
+            return CanisterHttpResponse {
+                status_code: 200,
+                headers: vec![
+                    ("Content-Type".to_string(), "application/json".to_string()),
+                    ("Cache-Control".to_string(), "no-store".to_string()),
+                ],
+                body: meta_payload.into_bytes(),
+            };
         }
-    });
+    }
+    let representation = negotiate_representation(req, route);
+    let payload = STATE.with(|state| representation_body(&state.borrow(), &representation));
     let mut headers = vec![
-        ("Content-Type".to_string(), "application/json".to_string()),
+        ("Content-Type".to_string(), representation.format.content_type().to_string()),
         ("Cache-Control".to_string(), "public, max-age=60".to_string()),
+        ("Vary".to_string(), "Accept, Accept-Encoding".to_string()),
     ];
-    if let Some(cert_header) = build_certificate_header(&path) {
-        headers.push(("IC-Certificate".to_string(), cert_header));
+    if representation.format == Format::Csv {
+        let file_name = representation.key.trim_start_matches('/');
+        let disposition = format!("attachment; filename=\"{file_name}\"");
+        headers.push(("Content-Disposition".to_string(), disposition));
+    }
+    let key = &representation.key;
+    let encoded = ENCODED_BODIES.with(|bodies| {
+        let bodies = bodies.borrow();
+        let variants = bodies.get(key)?;
+        match negotiate_encoding(req) {
+            ContentEncoding::Identity => None,
+            ContentEncoding::Gzip => Some(("gzip", variants.gzip.clone())),
+            ContentEncoding::Brotli => Some(("br", variants.brotli.clone())),
+        }
+    });
+    if representation.format != Format::Cbor {
+        if let Some(cert_header) = build_certificate_header(key) {
+            headers.push(("IC-Certificate".to_string(), cert_header));
+        }
     }
+    let body = match encoded {
+        Some((encoding, body)) => {
+            headers.push(("Content-Encoding".to_string(), encoding.to_string()));
+            body
+        }
+        None => payload,
+    };
     CanisterHttpResponse {
         status_code: 200,
         headers,
-        body: payload.into_bytes(),
+        body,
+    }
+}
+
+/// 405 for a known route. Like 404 it is uncertified: v1 certification only
+/// covers the body served under a path, so use the raw domain.
+fn method_not_allowed() -> CanisterHttpResponse {
+    CanisterHttpResponse {
+        status_code: 405,
+        headers: vec![
+            ("Content-Type".to_string(), "text/plain".to_string()),
+            ("Allow".to_string(), ALLOWED_METHODS.to_string()),
+        ],
+        body: METHOD_NOT_ALLOWED_BODY.to_vec(),
+    }
+}
+
+/// JSON, CSV and CBOR forms of a route, its own format first. CSV exists only
+/// where a `.csv` sibling route does; CBOR is derived from the JSON body.
+fn representations(route: &Route) -> Vec<Representation> {
+    let stem = route.path.strip_suffix(".json").or_else(|| route.path.strip_suffix(".csv"));
+    let sibling = |extension: &str| {
+        let path = format!("{}.{extension}", stem?);
+        ROUTES
+            .iter()
+            .any(|other| other.certified && other.path == path)
+            .then_some(path)
+    };
+    let own_csv = route.path.ends_with(".csv");
+    let json_key = if own_csv { sibling("json") } else { Some(route.path.to_string()) };
+    let csv_key = if own_csv { Some(route.path.to_string()) } else { sibling("csv") };
+    let cbor_key = json_key.as_ref().map(|path| format!("{path}#cbor"));
+    let mut representations: Vec<Representation> =
+        [(Format::Json, json_key), (Format::Csv, csv_key), (Format::Cbor, cbor_key)]
+            .into_iter()
+            .filter_map(|(format, key)| Some(Representation { format, key: key? }))
+            .collect();
+    let own = if own_csv { Format::Csv } else { Format::Json };
+    representations.sort_by_key(|representation| representation.format != own);
+    representations
+}
+
+/// Picks the representation with the highest `Accept` quality, preferring the
+/// route's own format on ties. Like brotli, other formats are only served on
+/// the raw domain, since certified gateways verify the path's own body.
+fn negotiate_representation(req: &CanisterHttpRequest, route: &Route) -> Representation {
+    let mut representations = representations(route);
+    let accept = request_header(req, "accept").filter(|_| is_raw_domain(req));
+    let mut best = 0;
+    if let Some(accept) = accept {
+        let mut best_quality = 0.0;
+        for (index, representation) in representations.iter().enumerate() {
+            let quality = accept_quality(accept, representation.format.media_type());
+            if quality > best_quality {
+                best = index;
+                best_quality = quality;
+            }
+        }
+    }
+    representations.swap_remove(best)
+}
+
+/// Quality the `Accept` header gives `media_type`, from its most specific
+/// matching range; 0 when nothing matches.
+fn accept_quality(accept: &str, media_type: &str) -> f32 {
+    let kind = media_type.split('/').next().unwrap_or_default();
+    let mut best: Option<(u8, f32)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let range = parts.next().unwrap_or_default();
+        let specificity = if range.eq_ignore_ascii_case(media_type) {
+            2
+        } else if range
+            .strip_suffix("/*")
+            .is_some_and(|range_kind| range_kind.eq_ignore_ascii_case(kind))
+        {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let quality = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|quality| quality.trim().parse().ok())
+            .unwrap_or(1.0);
+        if !matches!(best, Some((best_specificity, _)) if best_specificity >= specificity) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+fn representation_body(state: &State, representation: &Representation) -> Vec<u8> {
+    match representation.key.strip_suffix("#cbor") {
+        Some(json_path) => json_to_cbor(&route_payload(state, json_path)),
+        None => route_payload(state, &representation.key).into_bytes(),
     }
 }
 
+fn json_to_cbor(payload: &str) -> Vec<u8> {
+    let value: Value = serde_json::from_str(payload).unwrap_or(Value::Null);
+    serde_cbor::to_vec(&value).unwrap_or_default()
+}
+
 #[ic_cdk::query]
 fn transform(args: TransformArgs) -> HttpResponse {
     let mut response = args.response;
     response.headers.clear();
+    response.body = if response.status == 200u16 {
+        canonicalize_graphql_body(&response.body, &args.context)
+    } else {
+        Vec::new()
+    };
+    response
+}
+
+#[ic_cdk::query]
+fn transform_json(args: TransformArgs) -> HttpResponse {
+    let mut response = args.response;
+    response.headers.clear();
+    response.body = if response.status == 200u16 {
+        canonicalize_json_body(&response.body, &args.context)
+    } else {
+        Vec::new()
+    };
     response
 }
 
+/// Keeps the comma-separated top-level keys listed in `context` (all keys when
+/// empty) and re-serializes with ordered keys.
+fn canonicalize_json_body(body: &[u8], context: &[u8]) -> Vec<u8> {
+    let parsed: Value = match serde_json::from_slice(body) {
+        Ok(parsed) => parsed,
+        Err(_) => return Vec::new(),
+    };
+    let keys = std::str::from_utf8(context).unwrap_or_default();
+    let canonical = if keys.is_empty() {
+        parsed
+    } else {
+        let mut kept = serde_json::Map::new();
+        for key in keys.split(',') {
+            kept.insert(key.to_string(), parsed.get(key).cloned().unwrap_or(Value::Null));
+        }
+        Value::Object(kept)
+    };
+    serde_json::to_vec(&canonical).unwrap_or_default()
+}
+
+/// Reduces a GraphQL response to `data.<key>` for the comma-separated keys in
+/// `context` (or the error messages), then re-serializes it. `serde_json` maps
+/// are ordered, so replicas that saw differently ordered keys or extra fields
+/// such as `extensions` still agree on the bytes.
+fn canonicalize_graphql_body(body: &[u8], context: &[u8]) -> Vec<u8> {
+    let parsed: Value = match serde_json::from_slice(body) {
+        Ok(parsed) => parsed,
+        Err(_) => return Vec::new(),
+    };
+
+    let errors = parsed.get("errors").and_then(|errors| errors.as_array());
+    let canonical = if let Some(errors) = errors {
+        let messages: Vec<Value> = errors
+            .iter()
+            .map(|error| {
+                let message = error.get("message").cloned().unwrap_or(Value::Null);
+                json!({ "message": message })
+            })
+            .collect();
+        json!({ "errors": messages })
+    } else {
+        let data = parsed.get("data").cloned().unwrap_or(Value::Null);
+        let keys = std::str::from_utf8(context).unwrap_or_default();
+        if keys.is_empty() {
+            json!({ "data": data })
+        } else {
+            let mut kept = serde_json::Map::new();
+            for key in keys.split(',') {
+                kept.insert(key.to_string(), data.get(key).cloned().unwrap_or(Value::Null));
+            }
+            json!({ "data": kept })
+        }
+    };
+
+    serde_json::to_vec(&canonical).unwrap_or_default()
+}
+
+/// Prometheus text exposition of canister internals. Rendered per query, so it
+/// is served uncertified; scrape it through `raw.icp0.io`.
+fn render_metrics() -> String {
+    let balance = canister_balance128();
+    let mut out = String::new();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let counters = &state.counters;
+        push_metric(
+            &mut out,
+            "heap_memory_bytes",
+            "gauge",
+            "Wasm heap size in bytes.",
+            &[("", heap_memory_bytes().to_string())],
+        );
+        push_metric(
+            &mut out,
+            "stable_memory_bytes",
+            "gauge",
+            "Stable memory size in bytes.",
+            &[("", (ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE).to_string())],
+        );
+        push_metric(
+            &mut out,
+            "cycles_balance",
+            "gauge",
+            "Current cycles balance.",
+            &[("", balance.to_string())],
+        );
+        push_metric(
+            &mut out,
+            "cycles_spent_total",
+            "counter",
+            "Cycles attached to HTTPS outcalls.",
+            &[("", state.cycles.total_spent.to_string())],
+        );
+        push_metric(
+            &mut out,
+            "series_points",
+            "gauge",
+            "Number of points per series.",
+            &[
+                ("series=\"active_wallets\"", state.series.len().to_string()),
+                ("series=\"extrinsics\"", state.extrinsics_series.len().to_string()),
+                ("series=\"cycles_history\"", state.cycles_history.len().to_string()),
+            ],
+        );
+        push_metric(
+            &mut out,
+            "last_updated_timestamp_seconds",
+            "gauge",
+            "Unix time of the last payload change.",
+            &[("", (state.last_updated.unwrap_or(0) / 1_000_000_000).to_string())],
+        );
+        push_metric(
+            &mut out,
+            "last_refresh_timestamp_seconds",
+            "gauge",
+            "Unix time of the last successful refresh.",
+            &[("", (counters.last_refresh_at.unwrap_or(0) / 1_000_000_000).to_string())],
+        );
+        push_metric(
+            &mut out,
+            "refresh_total",
+            "counter",
+            "Refresh runs by outcome.",
+            &[
+                ("outcome=\"success\"", counters.refresh_success.to_string()),
+                ("outcome=\"failure\"", counters.refresh_failure.to_string()),
+            ],
+        );
+        push_metric(
+            &mut out,
+            "outcalls_total",
+            "counter",
+            "HTTPS outcalls by outcome.",
+            &[
+                (
+                    "outcome=\"success\"",
+                    (counters.outcalls - counters.outcall_failures).to_string(),
+                ),
+                ("outcome=\"failure\"", counters.outcall_failures.to_string()),
+            ],
+        );
+        push_metric(
+            &mut out,
+            "outcall_bytes_total",
+            "counter",
+            "HTTPS outcall body bytes by direction.",
+            &[
+                ("direction=\"request\"", counters.outcall_request_bytes.to_string()),
+                ("direction=\"response\"", counters.outcall_response_bytes.to_string()),
+            ],
+        );
+        push_metric(
+            &mut out,
+            "ingest_calls_total",
+            "counter",
+            "Ingest calls by endpoint.",
+            &[
+                ("endpoint=\"ingest_daily_snapshot\"", counters.ingest_snapshot_calls.to_string()),
+                (
+                    "endpoint=\"ingest_new_wallets_inflow\"",
+                    counters.ingest_inflow_calls.to_string(),
+                ),
+            ],
+        );
+    });
+    out
+}
+
+fn push_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, String)]) {
+    out.push_str(&format!("# HELP reef_metrics_{name} {help}\n"));
+    out.push_str(&format!("# TYPE reef_metrics_{name} {kind}\n"));
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            out.push_str(&format!("reef_metrics_{name} {value}\n"));
+        } else {
+            out.push_str(&format!("reef_metrics_{name}{{{labels}}} {value}\n"));
+        }
+    }
+}
+
+fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+fn wants_meta(url: &str) -> bool {
+    url.split_once('?')
+        .map(|(_, query)| query.split('&').any(|pair| pair == "meta=1"))
+        .unwrap_or(false)
+}
+
 fn normalize_path(url: &str) -> String {
     let mut path = url.split('?').next().unwrap_or("/");
     if let Some(scheme_idx) = path.find("://") {
@@ -616,38 +4237,170 @@ fn normalize_path(url: &str) -> String {
     }
 }
 
-fn sync_certified_data() {
-    let (payload, extrinsics_payload, inflow_payload) = STATE.with(|state| {
-        let state = state.borrow();
-        (
-            state.payload.clone(),
-            state.extrinsics_payload.clone(),
-            state.inflow_payload.clone(),
-        )
-    });
-    update_certified_data(&payload, &extrinsics_payload, &inflow_payload);
+fn route_payload(state: &State, path: &str) -> String {
+    match path {
+        "/extrinsics-daily.json" => state.extrinsics_payload.clone(),
+        "/new-wallets-inflow.json" => state.inflow_payload.clone(),
+        "/health.json" => state.health_payload.clone(),
+        "/active-wallets-daily.csv" => {
+            payload_to_csv(&state.payload, "series", &["ts", "active", "new"])
+        }
+        "/extrinsics-daily.csv" => {
+            payload_to_csv(&state.extrinsics_payload, "series", &["ts", "extrinsics"])
+        }
+        "/new-wallets-inflow.csv" => payload_to_csv(
+            &state.inflow_payload,
+            "entries",
+            &["address", "incomingRaw", "incomingReef"],
+        ),
+        INDEX_PATH => build_index(state),
+        "/openapi.json" => build_openapi(),
+        _ => state.payload.clone(),
+    }
+}
+
+/// Renders the `key` array of a JSON payload as CSV, so CSV routes carry
+/// exactly the rows (gap nulls included) of their JSON counterparts. Nulls
+/// and missing fields become empty cells.
+fn payload_to_csv(payload: &str, key: &str, columns: &[&str]) -> String {
+    let mut csv = columns.join(",");
+    csv.push_str("\r\n");
+    let payload: Value = serde_json::from_str(payload).unwrap_or(Value::Null);
+    let rows = payload.get(key).and_then(Value::as_array).map(Vec::as_slice);
+    for row in rows.unwrap_or_default() {
+        let cells: Vec<String> = columns
+            .iter()
+            .map(|column| match row.get(*column) {
+                Some(Value::String(value)) => csv_escape(value),
+                Some(Value::Null) | None => String::new(),
+                Some(value) => value.to_string(),
+            })
+            .collect();
+        csv.push_str(&cells.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Certifies every route body under its path. Gateways hash the decoded body
+/// of gzip responses, so the path entry also covers the gzip variant; each
+/// compressed variant's own hash is added under `<path>#gzip` / `<path>#br`.
+///
+/// The index lists the other routes' hashes, so it is certified last.
+fn update_certified_data() {
+    let bodies: Vec<(&str, String)> = STATE.with(|state| {
+        let state = state.borrow();
+        ROUTES
+            .iter()
+            .filter(|route| route.certified && route.path != INDEX_PATH)
+            .map(|route| (route.path, route_payload(&state, route.path)))
+            .collect()
+    });
+    for (path, body) in bodies {
+        certify_body(path, &body);
+    }
+    let index = STATE.with(|state| build_index(&state.borrow()));
+    certify_body(INDEX_PATH, &index);
+    CERT_TREE.with(|tree| {
+        let root_hash = tree.borrow().root_hash();
+        let labeled_hash = labeled_hash(CERT_LABEL, &root_hash);
+        set_certified_data(&labeled_hash);
+    });
+}
+
+/// Inserts the hashes of `body` and its compressed variants, recompressing
+/// only when the body changed.
+fn certify_body(path: &str, body: &str) {
+    let hash = sha256_hash(body.as_bytes());
+    let unchanged = CERT_TREE.with(|tree| tree.borrow().get(path.as_bytes()) == Some(&hash))
+        && ENCODED_BODIES.with(|encoded| encoded.borrow().contains_key(path));
+    if unchanged {
+        return;
+    }
+    let encoded = EncodedBodies {
+        gzip: gzip(body.as_bytes()),
+        brotli: brotli(body.as_bytes()),
+    };
+    CERT_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.insert(path.to_string(), hash);
+        tree.insert(format!("{path}#gzip"), sha256_hash(&encoded.gzip));
+        tree.insert(format!("{path}#br"), sha256_hash(&encoded.brotli));
+    });
+    ENCODED_BODIES.with(|bodies| bodies.borrow_mut().insert(path.to_string(), encoded));
+}
+
+fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    // Writing into a Vec cannot fail.
+    let _ = encoder.write_all(bytes);
+    encoder.finish().unwrap_or_default()
+}
+
+fn brotli(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder =
+        brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW_BITS);
+    let _ = encoder.write_all(bytes);
+    encoder.into_inner()
+}
+
+/// Picks the best encoding the client accepts. Brotli is only offered on the
+/// raw domain: certified gateways verify the decoded body and cannot decode
+/// brotli, so a `br` response there would fail verification.
+fn negotiate_encoding(req: &CanisterHttpRequest) -> ContentEncoding {
+    let Some(accept) = request_header(req, "accept-encoding") else {
+        return ContentEncoding::Identity;
+    };
+    if is_raw_domain(req) && accepts_token(accept, "br") {
+        ContentEncoding::Brotli
+    } else if accepts_token(accept, "gzip") {
+        ContentEncoding::Gzip
+    } else {
+        ContentEncoding::Identity
+    }
+}
+
+/// Appends `value` to the response's `Vary` header, creating it if needed.
+fn add_vary(headers: &mut Vec<(String, String)>, value: &str) {
+    match headers.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case("vary")) {
+        Some((_, vary)) => {
+            vary.push_str(", ");
+            vary.push_str(value);
+        }
+        None => headers.push(("Vary".to_string(), value.to_string())),
+    }
+}
+
+fn is_raw_domain(req: &CanisterHttpRequest) -> bool {
+    request_header(req, "host").is_some_and(|host| host.contains(".raw."))
 }
 
-fn update_certified_data(payload: &str, extrinsics_payload: &str, inflow_payload: &str) {
-    let hash = sha256_hash(payload.as_bytes());
-    let extrinsics_hash = sha256_hash(extrinsics_payload.as_bytes());
-    let inflow_hash = sha256_hash(inflow_payload.as_bytes());
-    CERT_TREE.with(|tree| {
-        let mut tree = tree.borrow_mut();
-        for path in CERTIFIED_PATHS {
-            let path_hash = if path == "/extrinsics-daily.json" {
-                extrinsics_hash
-            } else if path == "/new-wallets-inflow.json" {
-                inflow_hash
-            } else {
-                hash
-            };
-            tree.insert(path.to_string(), path_hash);
-        }
-        let root_hash = tree.root_hash();
-        let labeled_hash = labeled_hash(CERT_LABEL, &root_hash);
-        set_certified_data(&labeled_hash);
-    });
+fn request_header<'a>(req: &'a CanisterHttpRequest, name: &str) -> Option<&'a str> {
+    req.headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Whether a comma-separated header such as `Accept-Encoding` lists `token`
+/// without `q=0`.
+fn accepts_token(header: &str, token: &str) -> bool {
+    header.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let rejected = parts.any(|param| {
+            param.strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)
+        });
+        name.eq_ignore_ascii_case(token) && !rejected
+    })
 }
 
 fn sha256_hash(bytes: &[u8]) -> Hash {
@@ -684,6 +4437,127 @@ fn current_time() -> Result<OffsetDateTime, String> {
         .map_err(|_| "invalid timestamp nanos".to_string())
 }
 
+/// Snapshots the day's current values before they are overwritten.
+fn save_revision(state: &mut State, ts: &str) {
+    let revision = PointRevision {
+        ts: ts.to_string(),
+        replaced_at: time(),
+        point: state.series.iter().find(|point| point.ts == ts).cloned(),
+        extrinsics: state.extrinsics_series.iter().find(|point| point.ts == ts).cloned(),
+        provenance: state.provenance.iter().find(|entry| entry.ts == ts).cloned(),
+    };
+    let oldest_kept = current_time()
+        .map(|now| (now.date() - TimeDuration::days(DEFAULT_DAYS as i64)).to_string())
+        .unwrap_or_default();
+    push_revision(&mut state.revisions, revision, &oldest_kept);
+}
+
+/// Appends `revision`, dropping revisions of days before `oldest_kept` (the
+/// retention window) and keeping the newest `MAX_REVISIONS_PER_DAY` per day.
+fn push_revision(revisions: &mut Vec<PointRevision>, revision: PointRevision, oldest_kept: &str) {
+    let ts = revision.ts.clone();
+    revisions.push(revision);
+    revisions.retain(|revision| revision.ts.as_str() >= oldest_kept);
+    let kept = revisions.iter().filter(|revision| revision.ts == ts).count();
+    if kept > MAX_REVISIONS_PER_DAY {
+        let mut excess = kept - MAX_REVISIONS_PER_DAY;
+        revisions.retain(|revision| {
+            if excess > 0 && revision.ts == ts {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+    }
+}
+
+/// Puts a revision's values back in place, removing the day if it did not
+/// exist at that point. Returns the day if its daily point was removed.
+fn restore_revision(state: &mut State, revision: PointRevision) -> Option<String> {
+    let ts = revision.ts;
+    let removed = revision.point.is_none() && state.series.iter().any(|point| point.ts == ts);
+    state.series.retain(|point| point.ts != ts);
+    state.extrinsics_series.retain(|point| point.ts != ts);
+    state.provenance.retain(|entry| entry.ts != ts);
+    if let Some(point) = revision.point {
+        upsert_daily_point(&mut state.series, point);
+    }
+    if let Some(point) = revision.extrinsics {
+        upsert_extrinsics_point(&mut state.extrinsics_series, point);
+    }
+    if let Some(provenance) = revision.provenance {
+        state.provenance.push(provenance);
+        state.provenance.sort_by(|a, b| a.ts.cmp(&b.ts));
+    }
+    removed.then_some(ts)
+}
+
+/// Rebuilds the daily payloads after an out-of-band change to the series.
+/// `removed` lists days whose daily point was removed; the paged wallet set
+/// only goes stale when today's is among them. Callers must recertify.
+fn rebuild_daily_payloads(state: &mut State, removed: &[String]) {
+    build_series_payloads(state);
+    state.last_updated = Some(time());
+    if removed.contains(&today_label()) {
+        state.prev_active_wallets.clear();
+    }
+    state.versions.daily += 1;
+}
+
+/// Replaces the day's provenance and drops entries for days no longer kept.
+fn record_provenance(state: &mut State, provenance: Provenance) {
+    let series = &state.series;
+    state.provenance.retain(|entry| {
+        entry.ts != provenance.ts && series.iter().any(|point| point.ts == entry.ts)
+    });
+    state.provenance.push(provenance);
+    state.provenance.sort_by(|a, b| a.ts.cmp(&b.ts));
+}
+
+/// Builds the `?meta=1` variant of a series payload: each point gains a
+/// `meta` object with its provenance (`null` when unknown).
+fn build_meta_payload<T: Serialize>(series: &[T], provenance: &[Provenance]) -> String {
+    let series: Vec<Value> = series
+        .iter()
+        .map(|point| {
+            let mut point = serde_json::to_value(point).unwrap_or(Value::Null);
+            if let Value::Object(fields) = &mut point {
+                let ts = fields.get("ts").and_then(|ts| ts.as_str()).unwrap_or_default();
+                let meta = provenance
+                    .iter()
+                    .find(|entry| entry.ts == ts)
+                    .and_then(|entry| serde_json::to_value(entry).ok())
+                    .unwrap_or(Value::Null);
+                fields.insert("meta".to_string(), meta);
+            }
+            point
+        })
+        .collect();
+    json!({ "days": DEFAULT_DAYS, "series": series }).to_string()
+}
+
+/// New wallets for a collected day and whether that value is complete. A
+/// backfilled past day (or a first refresh) has no previous wallet set to
+/// diff against, so it keeps the value already stored for the day, e.g. from
+/// ingest, and is incomplete only when there is none.
+fn resolve_new_wallets(
+    state: &State,
+    day: &str,
+    aggregated: Option<u64>,
+    backfill: bool,
+    seen: &HashSet<String>,
+) -> (u64, bool) {
+    if let Some(new_wallets) = aggregated {
+        return (new_wallets, true);
+    }
+    if backfill || state.prev_active_wallets.is_empty() {
+        let stored = state.series.iter().find(|point| point.ts == day);
+        return stored.map_or((0, false), |point| (point.new_wallets, true));
+    }
+    let prev_set: HashSet<String> = state.prev_active_wallets.iter().cloned().collect();
+    (seen.difference(&prev_set).count() as u64, true)
+}
+
 fn upsert_daily_point(series: &mut Vec<DailyPoint>, point: DailyPoint) {
     series.retain(|entry| entry.ts != point.ts);
     series.push(point);
@@ -704,12 +4578,415 @@ fn upsert_extrinsics_point(series: &mut Vec<DailyExtrinsicsPoint>, point: DailyE
     }
 }
 
-fn build_payload(series: &[DailyPoint]) -> String {
-    json!({ "days": DEFAULT_DAYS, "series": series }).to_string()
+/// Average daily burn across the sampled window. Top-ups show up as balance
+/// increases and are ignored rather than netted against spending.
+fn burn_rate_per_day(samples: &[CyclesSample]) -> Option<u128> {
+    let first = samples.first()?;
+    let last = samples.last()?;
+    let elapsed = last.at.saturating_sub(first.at) as u128;
+    if elapsed == 0 {
+        return None;
+    }
+    let burned: u128 = samples
+        .windows(2)
+        .map(|pair| pair[0].balance.saturating_sub(pair[1].balance))
+        .sum();
+    Some(burned * NANOS_PER_DAY / elapsed)
 }
 
-fn build_extrinsics_payload(series: &[DailyExtrinsicsPoint]) -> String {
-    json!({ "days": DEFAULT_DAYS, "series": series }).to_string()
+/// Days until the balance reaches `min_balance`, the floor the owner keeps
+/// above the freezing threshold.
+fn days_until_freeze(balance: u128, min_balance: u128, burn_rate_per_day: Option<u128>) -> Option<f64> {
+    let burn = burn_rate_per_day.filter(|burn| *burn > 0)?;
+    Some(balance.saturating_sub(min_balance) as f64 / burn as f64)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Data routes change with `last_updated`; `/health.json` with each cycles
+/// sample. Generated documents have no update time.
+fn route_last_modified(state: &State, path: &str) -> Option<u64> {
+    match path {
+        INDEX_PATH | "/openapi.json" | METRICS_PATH => None,
+        "/health.json" => state.cycles_history.last().map(|sample| sample.at),
+        _ => state.last_updated,
+    }
+}
+
+/// `/` body: every route with its last update time and the hex SHA-256 of
+/// its certified identity body (`null` for `/` itself and uncertified routes).
+fn build_index(state: &State) -> String {
+    let routes: Vec<Value> = ROUTES
+        .iter()
+        .map(|route| {
+            let sha256 = if route.certified && route.path != INDEX_PATH {
+                let key = route.path.as_bytes();
+                CERT_TREE.with(|tree| tree.borrow().get(key).map(|hash| hex(hash)))
+            } else {
+                None
+            };
+            json!({
+                "path": route.path,
+                "description": route.description,
+                "contentType": route.content_type,
+                "certified": route.certified,
+                "lastUpdated": route_last_modified(state, route.path),
+                "sha256": sha256,
+                "query": route.query.iter().map(|param| param.name).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    json!({ "name": env!("CARGO_PKG_NAME"), "openapi": "/openapi.json", "routes": routes })
+        .to_string()
+}
+
+fn build_openapi() -> String {
+    let paths: serde_json::Map<String, Value> = ROUTES
+        .iter()
+        .map(|route| {
+            let parameters: Vec<Value> = route
+                .query
+                .iter()
+                .map(|param| {
+                    json!({
+                        "name": param.name,
+                        "in": "query",
+                        "required": false,
+                        "description": param.description,
+                        "schema": { "type": "string" },
+                    })
+                })
+                .collect();
+            let mut content = serde_json::Map::new();
+            if route.certified {
+                for representation in representations(route) {
+                    let schema = ROUTES
+                        .iter()
+                        .find(|other| other.path == representation.key)
+                        .map_or_else(binary_schema, |other| (other.schema)());
+                    let media_type = representation.format.media_type().to_string();
+                    content.insert(media_type, json!({ "schema": schema }));
+                }
+            } else {
+                let schema = (route.schema)();
+                content.insert(route.content_type.to_string(), json!({ "schema": schema }));
+            }
+            let mut responses = json!({ "200": { "description": "OK", "content": content } });
+            responses["405"] = json!({
+                "description": "Method not allowed",
+                "headers": { "Allow": { "schema": { "type": "string" } } },
+            });
+            let operation = json!({
+                "summary": route.description,
+                "parameters": parameters,
+                "responses": responses,
+            });
+            (route.path.to_string(), json!({ "get": operation.clone(), "head": operation }))
+        })
+        .collect();
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+    })
+    .to_string()
+}
+
+fn object_schema() -> Value {
+    json!({ "type": "object" })
+}
+
+fn binary_schema() -> Value {
+    json!({ "type": "string", "format": "binary" })
+}
+
+fn text_schema() -> Value {
+    json!({ "type": "string" })
+}
+
+fn nullable_integer() -> Value {
+    json!({ "type": "integer", "nullable": true })
+}
+
+fn series_schema(point_properties: Value) -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "days": { "type": "integer" },
+            "series": {
+                "type": "array",
+                "items": { "type": "object", "properties": point_properties },
+            },
+        },
+    })
+}
+
+fn daily_schema() -> Value {
+    series_schema(json!({
+        "ts": { "type": "string", "format": "date" },
+        "active": nullable_integer(),
+        "new": nullable_integer(),
+        "publisher": { "type": "string" },
+    }))
+}
+
+fn extrinsics_schema() -> Value {
+    series_schema(json!({
+        "ts": { "type": "string", "format": "date" },
+        "extrinsics": nullable_integer(),
+        "publisher": { "type": "string" },
+    }))
+}
+
+fn inflow_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "asOf": { "type": "string", "format": "date-time", "nullable": true },
+            "from": { "type": "string", "format": "date-time" },
+            "to": { "type": "string", "format": "date-time" },
+            "minRaw": { "type": "string" },
+            "totalNew": { "type": "integer" },
+            "qualified": { "type": "integer" },
+            "truncated": { "type": "boolean" },
+            "entries": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "address": { "type": "string" },
+                        "incomingRaw": { "type": "string" },
+                        "incomingReef": { "type": "string" },
+                    },
+                },
+            },
+        },
+    })
+}
+
+fn health_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "status": { "type": "string", "enum": ["unknown", "ok", "low", "critical"] },
+            "cyclesBalance": nullable_integer(),
+            "minBalance": { "type": "integer" },
+            "burnRatePerDay": nullable_integer(),
+            "daysUntilFreeze": { "type": "number", "nullable": true },
+            "spentToday": { "type": "integer" },
+            "dailyBudget": { "type": "integer" },
+            "sampledAt": nullable_integer(),
+            "lastUpdated": nullable_integer(),
+        },
+    })
+}
+
+fn index_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string" },
+            "openapi": { "type": "string" },
+            "routes": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string" },
+                        "description": { "type": "string" },
+                        "contentType": { "type": "string" },
+                        "certified": { "type": "boolean" },
+                        "lastUpdated": nullable_integer(),
+                        "sha256": { "type": "string", "nullable": true },
+                        "query": { "type": "array", "items": { "type": "string" } },
+                    },
+                },
+            },
+        },
+    })
+}
+
+fn build_health_payload(state: &State, balance: u128) -> String {
+    let burn_rate = burn_rate_per_day(&state.cycles_history);
+    let days_left = days_until_freeze(balance, state.cycles.min_balance, burn_rate);
+    let status = match days_left {
+        _ if balance < state.cycles.min_balance => "critical",
+        Some(days) if days < HEALTH_CRITICAL_DAYS => "critical",
+        Some(days) if days < HEALTH_WARN_DAYS => "low",
+        _ => "ok",
+    };
+    json!({
+        "status": status,
+        "cyclesBalance": balance,
+        "minBalance": state.cycles.min_balance,
+        "burnRatePerDay": burn_rate,
+        "daysUntilFreeze": days_left.map(|days| days.floor()),
+        "spentToday": state.cycles.spent_on(&today_label()),
+        "dailyBudget": state.cycles.daily_budget,
+        "sampledAt": state.cycles_history.last().map(|sample| sample.at),
+        "lastUpdated": state.last_updated,
+    })
+    .to_string()
+}
+
+fn build_series_payloads(state: &mut State) {
+    let window = if state.emit_gap_nulls { retention_days() } else { Vec::new() };
+    build_payloads_with_gaps(state, &window);
+}
+
+/// Each series gets `null` points only for the days of `window` it lacks.
+fn build_payloads_with_gaps(state: &mut State, window: &[String]) {
+    let gaps = missing_from(window, state.series.iter().map(|point| point.ts.as_str()));
+    let extrinsics_gaps =
+        missing_from(window, state.extrinsics_series.iter().map(|point| point.ts.as_str()));
+    state.payload = build_payload(&state.series, &gaps);
+    state.extrinsics_payload = build_extrinsics_payload(&state.extrinsics_series, &extrinsics_gaps);
+}
+
+/// `gaps` are emitted as explicit `null` points so charts show the hole.
+fn build_payload(series: &[DailyPoint], gaps: &[String]) -> String {
+    if gaps.is_empty() {
+        return json!({ "days": DEFAULT_DAYS, "series": series }).to_string();
+    }
+    let nulls = gaps.iter().map(|ts| json!({ "ts": ts, "active": null, "new": null }));
+    json!({ "days": DEFAULT_DAYS, "series": with_gap_points(series, nulls) }).to_string()
+}
+
+fn build_extrinsics_payload(series: &[DailyExtrinsicsPoint], gaps: &[String]) -> String {
+    if gaps.is_empty() {
+        return json!({ "days": DEFAULT_DAYS, "series": series }).to_string();
+    }
+    let nulls = gaps.iter().map(|ts| json!({ "ts": ts, "extrinsics": null }));
+    json!({ "days": DEFAULT_DAYS, "series": with_gap_points(series, nulls) }).to_string()
+}
+
+fn with_gap_points<T: Serialize>(series: &[T], nulls: impl Iterator<Item = Value>) -> Vec<Value> {
+    let mut points: Vec<Value> = series
+        .iter()
+        .filter_map(|point| serde_json::to_value(point).ok())
+        .chain(nulls)
+        .collect();
+    points.sort_by(|a, b| {
+        let ts = |point: &Value| point.get("ts").and_then(|ts| ts.as_str()).map(str::to_string);
+        ts(a).cmp(&ts(b))
+    });
+    points
+}
+
+/// Days in the retention window before today that lack an active-wallets or
+/// extrinsics point.
+fn missing_days(state: &State) -> Vec<String> {
+    let window = retention_days();
+    let gaps = missing_from(&window, state.series.iter().map(|point| point.ts.as_str()));
+    let extrinsics_gaps =
+        missing_from(&window, state.extrinsics_series.iter().map(|point| point.ts.as_str()));
+    window
+        .into_iter()
+        .filter(|day| gaps.contains(day) || extrinsics_gaps.contains(day))
+        .collect()
+}
+
+/// Days of the retention window before today, oldest first. Today is excluded
+/// because it may not be collected yet.
+fn retention_days() -> Vec<String> {
+    let Ok(now) = current_time() else {
+        return Vec::new();
+    };
+    let today = now.date();
+    (1..DEFAULT_DAYS as i64)
+        .rev()
+        .map(|back| (today - TimeDuration::days(back)).to_string())
+        .collect()
+}
+
+/// Days of `window` with no point among `present`.
+fn missing_from<'a>(window: &[String], present: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let present: HashSet<&str> = present.collect();
+    window.iter().filter(|day| !present.contains(day.as_str())).cloned().collect()
+}
+
+/// Fills the pending collection's aggregated values for every metric with a
+/// non-paging strategy. Failures are logged and leave the value unset so the
+/// metric falls back to paging.
+async fn resolve_aggregates(
+    graphql_url: &str,
+    strategies: &[(Metric, CollectionStrategy)],
+    pending: &mut PendingCollection,
+) {
+    for (metric, strategy) in strategies {
+        let value = match fetch_aggregate(graphql_url, *metric, strategy, pending).await {
+            Ok(value) => value,
+            Err(err) => {
+                log(
+                    LogLevel::Warn,
+                    "refresh",
+                    "aggregate strategy failed, falling back to paging",
+                    &[("metric", metric.as_str().to_string()), ("error", err)],
+                );
+                None
+            }
+        };
+        match metric {
+            Metric::ActiveWallets => pending.aggregated_active = value,
+            Metric::NewWallets => pending.aggregated_new_wallets = value,
+            Metric::Extrinsics => pending.aggregated_extrinsics = value,
+        }
+    }
+}
+
+async fn fetch_aggregate(
+    graphql_url: &str,
+    metric: Metric,
+    strategy: &CollectionStrategy,
+    pending: &PendingCollection,
+) -> Result<Option<u64>, String> {
+    match strategy {
+        CollectionStrategy::Paging => Ok(None),
+        CollectionStrategy::IndexerCount { query, count_path } => {
+            let data_key = count_path.split('.').next().unwrap_or_default();
+            let variables = json!({
+                "from": pending.from_iso,
+                "to": pending.to_iso,
+            });
+            let response_json = graphql_request(
+                graphql_url,
+                query,
+                variables,
+                data_key,
+                COUNT_MAX_RESPONSE_BYTES,
+            )
+            .await?;
+            let count = response_json
+                .get("data")
+                .and_then(|data| json_path(data, count_path))
+                .and_then(|count| count.as_u64())
+                .ok_or_else(|| format!("missing {count_path}"))?;
+            Ok(Some(count))
+        }
+        CollectionStrategy::AggregatorDaily { url } => {
+            let response_json =
+                json_get_request(url, "series", AGGREGATOR_MAX_RESPONSE_BYTES).await?;
+            let field = metric.aggregator_field();
+            let value = response_json
+                .get("series")
+                .and_then(|series| series.as_array())
+                .and_then(|series| {
+                    series.iter().find(|entry| {
+                        entry.get("ts").and_then(|ts| ts.as_str()) == Some(pending.day.as_str())
+                    })
+                })
+                .and_then(|entry| entry.get(field))
+                .and_then(|value| value.as_u64())
+                .ok_or_else(|| format!("aggregator has no {field} for {}", pending.day))?;
+            Ok(Some(value))
+        }
+    }
+}
+
+fn json_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, key| value.get(key))
 }
 
 async fn fetch_extrinsics_count(
@@ -717,12 +4994,119 @@ async fn fetch_extrinsics_count(
     from_iso: &str,
     to_iso: &str,
 ) -> Result<u64, String> {
-    let body = json!({
-        "query": EXTRINSICS_COUNT_QUERY,
-        "variables": {
-            "from": from_iso,
-            "to": to_iso,
+    let variables = json!({
+        "from": from_iso,
+        "to": to_iso,
+    });
+    let response_json = graphql_request(
+        graphql_url,
+        EXTRINSICS_COUNT_QUERY,
+        variables,
+        "extrinsicsConnection",
+        COUNT_MAX_RESPONSE_BYTES,
+    )
+    .await?;
+
+    let total = response_json
+        .get("data")
+        .and_then(|data| data.get("extrinsicsConnection"))
+        .and_then(|conn| conn.get("totalCount"))
+        .and_then(|count| count.as_u64())
+        .ok_or_else(|| "missing extrinsics totalCount".to_string())?;
+
+    Ok(total)
+}
+
+/// Fetches one page of transfers and returns the wallets it touches together
+/// with the cursor for the next page (`None` once the window is exhausted).
+async fn fetch_transfers_page(
+    graphql_url: &str,
+    from_iso: &str,
+    to_iso: &str,
+    after: Option<String>,
+) -> Result<(Vec<String>, Option<String>), String> {
+    let variables = json!({
+        "from": from_iso,
+        "to": to_iso,
+        "after": after,
+    });
+    let response_json = graphql_request(
+        graphql_url,
+        TRANSFERS_PAGE_QUERY,
+        variables,
+        "transfersConnection",
+        TRANSFERS_MAX_RESPONSE_BYTES,
+    )
+    .await?;
+
+    let connection = response_json
+        .get("data")
+        .and_then(|data| data.get("transfersConnection"))
+        .ok_or_else(|| "missing transfersConnection".to_string())?;
+
+    let edges = connection
+        .get("edges")
+        .and_then(|edges| edges.as_array())
+        .ok_or_else(|| "missing edges".to_string())?;
+
+    let mut wallets = Vec::with_capacity(edges.len() * 2);
+    for edge in edges {
+        let node = match edge.get("node") {
+            Some(node) => node,
+            None => continue,
+        };
+        if let Some(from_id) = node
+            .get("from")
+            .and_then(|from| from.get("id"))
+            .and_then(|id| id.as_str())
+        {
+            wallets.push(from_id.to_string());
+        }
+        if let Some(to_id) = node
+            .get("to")
+            .and_then(|to| to.get("id"))
+            .and_then(|id| id.as_str())
+        {
+            wallets.push(to_id.to_string());
         }
+    }
+
+    let page_info = connection
+        .get("pageInfo")
+        .and_then(|page_info| page_info.as_object())
+        .ok_or_else(|| "missing pageInfo".to_string())?;
+
+    let has_next = page_info
+        .get("hasNextPage")
+        .and_then(|flag| flag.as_bool())
+        .unwrap_or(false);
+    let end_cursor = page_info
+        .get("endCursor")
+        .and_then(|cursor| cursor.as_str())
+        .map(|cursor| cursor.to_string());
+
+    if !has_next {
+        return Ok((wallets, None));
+    }
+    if end_cursor.is_none() {
+        return Err("hasNextPage true but endCursor missing".to_string());
+    }
+    Ok((wallets, end_cursor))
+}
+
+/// Posts a GraphQL query. `data_key` names the field under `data` the caller
+/// reads; it is handed to `transform` so everything else is dropped before
+/// consensus.
+async fn graphql_request(
+    graphql_url: &str,
+    query: &str,
+    variables: Value,
+    data_key: &str,
+    max_response_bytes: u64,
+) -> Result<Value, String> {
+    let body = json!({
+        "query": query,
+        "variables": variables,
     });
     let body_bytes = serde_json::to_vec(&body)
         .map_err(|err| format!("failed to serialize graphql request: {err}"))?;
@@ -745,17 +5129,13 @@ async fn fetch_extrinsics_count(
             },
         ],
         body: Some(body_bytes),
-        max_response_bytes: Some(1_000_000),
-        transform: Some(TransformContext::from_name("transform".to_string(), vec![])),
+        max_response_bytes: Some(max_response_bytes),
+        transform: Some(TransformContext::from_name(
+            TRANSFORM_METHOD.to_string(),
+            data_key.as_bytes().to_vec(),
+        )),
     };
-
-    let (response,): (HttpResponse,) = ic_http::http_request(request, HTTP_CYCLES)
-        .await
-        .map_err(|(_, msg)| format!("http_request failed: {msg}"))?;
-
-    if response.status != 200u16 {
-        return Err(format!("upstream status {}", response.status));
-    }
+    let response = http_outcall(request, TRANSFORM_METHOD).await?;
 
     let response_json: Value = serde_json::from_slice(&response.body)
         .map_err(|err| format!("failed to parse graphql response: {err}"))?;
@@ -764,135 +5144,311 @@ async fn fetch_extrinsics_count(
         return Err(format!("graphql error: {errors}"));
     }
 
-    let total = response_json
-        .get("data")
-        .and_then(|data| data.get("extrinsicsConnection"))
-        .and_then(|conn| conn.get("totalCount"))
-        .and_then(|count| count.as_u64())
-        .ok_or_else(|| "missing extrinsics totalCount".to_string())?;
-
-    Ok(total)
+    Ok(response_json)
 }
 
-async fn fetch_active_wallets_set(
-    graphql_url: &str,
-    from_iso: &str,
-    to_iso: &str,
-) -> Result<HashSet<String>, String> {
-    let mut after: Option<String> = None;
-    let mut page = 0usize;
-    let mut active_wallets: HashSet<String> = HashSet::new();
-
-    loop {
-        page += 1;
-        if page > MAX_PAGES {
-            return Err(format!("exceeded max pages ({MAX_PAGES})"));
-        }
-
-        let body = json!({
-            "query": TRANSFERS_PAGE_QUERY,
-            "variables": {
-                "from": from_iso,
-                "to": to_iso,
-                "after": after,
-            }
-        });
-        let body_bytes = serde_json::to_vec(&body)
-            .map_err(|err| format!("failed to serialize graphql request: {err}"))?;
+/// GETs a JSON document, keeping only the top-level `keys` through
+/// `transform_json`.
+async fn json_get_request(
+    url: &str,
+    keys: &str,
+    max_response_bytes: u64,
+) -> Result<Value, String> {
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        method: HttpMethod::GET,
+        headers: vec![
+            HttpHeader {
+                name: "User-Agent".to_string(),
+                value: "reef-metrics-onchain".to_string(),
+            },
+            HttpHeader {
+                name: "Accept".to_string(),
+                value: "application/json".to_string(),
+            },
+        ],
+        body: None,
+        max_response_bytes: Some(max_response_bytes),
+        transform: Some(TransformContext::from_name(
+            TRANSFORM_JSON_METHOD.to_string(),
+            keys.as_bytes().to_vec(),
+        )),
+    };
+    let response = http_outcall(request, TRANSFORM_JSON_METHOD).await?;
 
-        let request = CanisterHttpRequestArgument {
-            url: graphql_url.to_string(),
-            method: HttpMethod::POST,
-            headers: vec![
-                HttpHeader {
-                    name: "User-Agent".to_string(),
-                    value: "reef-metrics-onchain".to_string(),
-                },
-                HttpHeader {
-                    name: "Accept".to_string(),
-                    value: "application/json".to_string(),
-                },
-                HttpHeader {
-                    name: "Content-Type".to_string(),
-                    value: "application/json".to_string(),
-                },
-            ],
-            body: Some(body_bytes),
-            max_response_bytes: Some(2_000_000),
-            transform: Some(TransformContext::from_name("transform".to_string(), vec![])),
-        };
+    serde_json::from_slice(&response.body)
+        .map_err(|err| format!("failed to parse json response: {err}"))
+}
 
-        let (response,): (HttpResponse,) = ic_http::http_request(request, HTTP_CYCLES)
-            .await
-            .map_err(|(_, msg)| format!("http_request failed: {msg}"))?;
+/// Prices and books the outcall against the cycles budget, performs it and
+/// records counters. Only `200` responses are returned.
+async fn http_outcall(
+    request: CanisterHttpRequestArgument,
+    transform_method: &str,
+) -> Result<HttpResponse, String> {
+    let url = request.url.clone();
+    let cycles = http_outcall_cost(&request, transform_method);
+    reserve_outcall_cycles(cycles).inspect_err(|err| {
+        log(LogLevel::Warn, "cycles", "outcall skipped", &[("reason", err.clone())]);
+    })?;
+    let request_bytes = request.body.as_ref().map(|body| body.len()).unwrap_or(0) as u64;
 
-        if response.status != 200u16 {
-            return Err(format!("upstream status {}", response.status));
+    let result = ic_http::http_request(request, cycles).await;
+    STATE.with(|state| {
+        let counters = &mut state.borrow_mut().counters;
+        counters.outcalls += 1;
+        counters.outcall_request_bytes += request_bytes;
+        match &result {
+            Ok((response,)) => counters.outcall_response_bytes += response.body.len() as u64,
+            Err(_) => counters.outcall_failures += 1,
         }
+    });
+    let (response,): (HttpResponse,) = result.map_err(|(_, msg)| {
+        log(LogLevel::Warn, "outcall", "http_request failed", &[("error", msg.clone())]);
+        format!("http_request failed: {msg}")
+    })?;
+
+    if response.status != 200u16 {
+        log(
+            LogLevel::Warn,
+            "outcall",
+            "unexpected upstream status",
+            &[("url", url), ("status", response.status.to_string())],
+        );
+        return Err(format!("upstream status {}", response.status));
+    }
+
+    Ok(response)
+}
+
+/// Cycles charged for an HTTPS outcall, per the IC fee schedule:
+/// `(3M + 60K·n)·n + 400·n·request_bytes + 800·n·max_response_bytes`.
+fn http_outcall_cost(request: &CanisterHttpRequestArgument, transform_method: &str) -> u128 {
+    let n = HTTP_SUBNET_SIZE;
+    let header_bytes: usize = request
+        .headers
+        .iter()
+        .map(|header| header.name.len() + header.value.len())
+        .sum();
+    let transform_bytes = request
+        .transform
+        .as_ref()
+        .map(|transform| transform_method.len() + transform.context.len())
+        .unwrap_or(0);
+    let request_bytes = (request.url.len()
+        + header_bytes
+        + request.body.as_ref().map(|body| body.len()).unwrap_or(0)
+        + transform_bytes) as u128;
+    let max_response_bytes = request.max_response_bytes.unwrap_or(2_000_000) as u128;
 
-        let response_json: Value = serde_json::from_slice(&response.body)
-            .map_err(|err| format!("failed to parse graphql response: {err}"))?;
+    (3_000_000 + 60_000 * n) * n + 400 * n * request_bytes + 800 * n * max_response_bytes
+}
+
+fn today_label() -> String {
+    current_time()
+        .map(|now| now.date().to_string())
+        .unwrap_or_default()
+}
 
-        if let Some(errors) = response_json.get("errors") {
-            return Err(format!("graphql error: {errors}"));
+/// Books `cycles` against the daily budget before an outcall is made. Fails
+/// without spending anything when the budget or the minimum balance would be
+/// crossed, which lets refresh abort cleanly.
+fn reserve_outcall_cycles(cycles: u128) -> Result<(), String> {
+    let today = today_label();
+    let balance = canister_balance128();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let ledger = &mut state.cycles;
+        if ledger.day != today {
+            ledger.day = today;
+            ledger.spent_today = 0;
+        }
+        if ledger.daily_budget > 0 && ledger.spent_today + cycles > ledger.daily_budget {
+            return Err(format!(
+                "daily cycles budget exhausted ({} of {} spent)",
+                ledger.spent_today, ledger.daily_budget
+            ));
         }
+        if balance < ledger.min_balance.saturating_add(cycles) {
+            return Err(format!(
+                "cycles balance {balance} below minimum {}",
+                ledger.min_balance
+            ));
+        }
+        ledger.spent_today += cycles;
+        ledger.refresh_spent += cycles;
+        ledger.total_spent += cycles;
+        Ok(())
+    })
+}
 
-        let connection = response_json
-            .get("data")
-            .and_then(|data| data.get("transfersConnection"))
-            .ok_or_else(|| "missing transfersConnection".to_string())?;
+ic_cdk::export_candid!();
 
-        let edges = connection
-            .get("edges")
-            .and_then(|edges| edges.as_array())
-            .ok_or_else(|| "missing edges".to_string())?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        for edge in edges {
-            let node = match edge.get("node") {
-                Some(node) => node,
-                None => continue,
-            };
-            if let Some(from_id) = node
-                .get("from")
-                .and_then(|from| from.get("id"))
-                .and_then(|id| id.as_str())
-            {
-                active_wallets.insert(from_id.to_string());
-            }
-            if let Some(to_id) = node
-                .get("to")
-                .and_then(|to| to.get("id"))
-                .and_then(|id| id.as_str())
-            {
-                active_wallets.insert(to_id.to_string());
-            }
+    #[test]
+    fn snapshot_message_matches_aggregator_signer() {
+        let snapshot = DailySnapshotInput {
+            ts: "2024-05-01".to_string(),
+            active: 1234,
+            new_wallets: 56,
+            extrinsics: 7890,
+            signature: None,
+        };
+        // Bytes of `snapshotMessage` in aggregator/src/icp-signing.ts for the same snapshot.
+        assert_eq!(
+            snapshot_message(&snapshot),
+            br#"{"active":1234,"extrinsics":7890,"new_wallets":56,"ts":"2024-05-01"}"#.to_vec()
+        );
+    }
+
+    #[test]
+    fn unsigned_inflow_rejected_when_signatures_required() {
+        let inflow = br#"{"entries":[]}"#;
+        assert_eq!(verify_signed("inflow", None, inflow), Ok(None));
+        STATE.with(|state| state.borrow_mut().require_signed_snapshots = true);
+        assert_eq!(
+            verify_signed("inflow", None, inflow),
+            Err("inflow signature required".to_string())
+        );
+    }
+
+    #[test]
+    fn revisions_pruned_by_retention_window() {
+        let revision = |ts: &str, replaced_at: u64| PointRevision {
+            ts: ts.to_string(),
+            replaced_at,
+            point: None,
+            extrinsics: None,
+            provenance: None,
+        };
+        let mut revisions = vec![revision("2024-04-01", 1), revision("2024-04-20", 2)];
+        push_revision(&mut revisions, revision("2024-05-01", 3), "2024-04-02");
+        let days: Vec<&str> = revisions.iter().map(|revision| revision.ts.as_str()).collect();
+        assert_eq!(days, ["2024-04-20", "2024-05-01"]);
+
+        for replaced_at in 0..MAX_REVISIONS_PER_DAY as u64 {
+            push_revision(&mut revisions, revision("2024-05-01", 10 + replaced_at), "2024-04-02");
         }
+        let kept: Vec<u64> = revisions
+            .iter()
+            .filter(|revision| revision.ts == "2024-05-01")
+            .map(|revision| revision.replaced_at)
+            .collect();
+        assert_eq!(kept.len(), MAX_REVISIONS_PER_DAY);
+        assert_eq!(kept.first(), Some(&10));
+    }
 
-        let page_info = connection
-            .get("pageInfo")
-            .and_then(|page_info| page_info.as_object())
-            .ok_or_else(|| "missing pageInfo".to_string())?;
+    #[test]
+    fn gap_nulls_follow_each_series() {
+        let mut state = State::new(Principal::anonymous());
+        state.series.push(DailyPoint {
+            ts: "2024-05-01".to_string(),
+            active: 10,
+            new_wallets: 2,
+            publisher: None,
+        });
+        state.extrinsics_series.push(DailyExtrinsicsPoint {
+            ts: "2024-05-02".to_string(),
+            extrinsics: 30,
+            publisher: None,
+        });
+        let window = ["2024-05-01", "2024-05-02"].map(String::from);
+        build_payloads_with_gaps(&mut state, &window);
 
-        let has_next = page_info
-            .get("hasNextPage")
-            .and_then(|flag| flag.as_bool())
-            .unwrap_or(false);
-        let end_cursor = page_info
-            .get("endCursor")
-            .and_then(|cursor| cursor.as_str())
-            .map(|cursor| cursor.to_string());
+        let points = |payload: &str| -> Vec<(String, bool)> {
+            let payload: Value = serde_json::from_str(payload).unwrap();
+            let series = payload["series"].as_array().unwrap();
+            series
+                .iter()
+                .map(|point| {
+                    let fields = point.as_object().unwrap();
+                    let null = fields.iter().all(|(key, value)| key == "ts" || value.is_null());
+                    (point["ts"].as_str().unwrap().to_string(), null)
+                })
+                .collect()
+        };
+        let day = |ts: &str, null: bool| (ts.to_string(), null);
+        assert_eq!(points(&state.payload), [day("2024-05-01", false), day("2024-05-02", true)]);
+        assert_eq!(
+            points(&state.extrinsics_payload),
+            [day("2024-05-01", true), day("2024-05-02", false)]
+        );
+    }
 
-        if has_next {
-            if end_cursor.is_none() {
-                return Err("hasNextPage true but endCursor missing".to_string());
-            }
-            after = end_cursor;
-        } else {
-            break;
+    #[test]
+    fn backfill_keeps_ingested_new_wallets() {
+        let mut state = State::new(Principal::anonymous());
+        state.series.push(DailyPoint {
+            ts: "2024-05-01".to_string(),
+            active: 100,
+            new_wallets: 42,
+            publisher: Some("aggregator".to_string()),
+        });
+        let seen: HashSet<String> = ["a", "b"].map(String::from).into();
+
+        assert_eq!(resolve_new_wallets(&state, "2024-05-01", None, true, &seen), (42, true));
+        assert_eq!(resolve_new_wallets(&state, "2024-05-02", None, true, &seen), (0, false));
+        assert_eq!(resolve_new_wallets(&state, "2024-05-01", Some(7), true, &seen), (7, true));
+    }
+
+    #[test]
+    fn failed_backfill_job_closes_run() {
+        let mut state = backfill_state();
+
+        for attempt in 1..=MAX_JOB_ATTEMPTS {
+            state.jobs[0].state = JobState::Running;
+            let outcome = Err("outcall failed".to_string());
+            apply_job_outcome(&mut state, 7, outcome, 10 + attempt as u64);
         }
+
+        assert!(state.jobs[0].state == JobState::Failed);
+        let progress = state.backfill_progress.as_ref().unwrap();
+        assert_eq!(progress.completed, 1);
+        assert_eq!(progress.finished_at, Some(10 + MAX_JOB_ATTEMPTS as u64));
+        assert_eq!(failed_backfill_days(&state), vec!["2024-05-01".to_string()]);
     }
 
-    Ok(active_wallets)
-}
+    #[test]
+    fn cancelled_running_backfill_ignores_late_outcome() {
+        let mut state = backfill_state();
 
-ic_cdk::export_candid!();
+        assert_eq!(cancel_backfill_jobs(&mut state, 5), 1);
+        apply_job_outcome(&mut state, 7, Err("outcall failed".to_string()), 6);
+
+        assert!(state.jobs[0].state == JobState::Cancelled);
+        assert_eq!(state.jobs[0].attempts, 0);
+        let progress = state.backfill_progress.as_ref().unwrap();
+        assert!(progress.cancelled);
+        assert_eq!((progress.completed, progress.finished_at), (0, Some(5)));
+        assert_eq!(failed_backfill_days(&state), vec!["2024-05-01".to_string()]);
+    }
+
+    /// A one-day backfill run whose job 7 is running.
+    fn backfill_state() -> State {
+        let mut state = State::new(Principal::anonymous());
+        state.backfill_progress = Some(BackfillProgress {
+            from_date: "2024-05-01".to_string(),
+            to_date: "2024-05-01".to_string(),
+            total: 1,
+            completed: 0,
+            started_at: 1,
+            finished_at: None,
+            cancelled: false,
+        });
+        state.jobs.push(Job {
+            id: 7,
+            kind: JobKind::Backfill { day: "2024-05-01".to_string() },
+            state: JobState::Running,
+            attempts: 0,
+            next_run_at: 1,
+            created_at: 1,
+            updated_at: 1,
+            last_error: None,
+            result: None,
+        });
+        state
+    }
+}